@@ -0,0 +1,365 @@
+use std::{sync::LazyLock, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::{
+    config::ApiAuth,
+    error::{ApiError, AppError},
+    extract::{CALLER_ID_HEADER, REQUEST_ID_HEADER},
+    sign::{self, SIGNATURE_HEADER},
+    uuid_cache::IdempotencyStore,
+    GLOBAL_CONFIG,
+};
+
+pub const API_KEY_HEADER: &str = "X-KSY-API-KEY";
+
+// the default body limit of the axum extractors, signed bodies are
+// buffered before the handler sees them
+pub const MAX_BODY: usize = 2 << 20;
+
+// Permission is one endpoint of /onePass a caller may be allowed to call
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    BatchPay,
+    BatchPayStatus,
+    UserTrade,
+    QueryUserAmount,
+    AccountHistory,
+    IdempotencyStats,
+    OpenAccount,
+    FreezeAccount,
+    UnfreezeAccount,
+    CloseAccount,
+}
+
+// SEEN_SIGNATURES holds the signatures accepted within the skew window,
+// a signature stops verifying after 2 * max_skew at the latest. A signature
+// is only ever dropped once it can not verify again, a full store rejects
+// signed requests instead. The store lives in memory: a restart forgets it,
+// so a request signed less than max_skew before the restart can be replayed
+// once after it
+static SEEN_SIGNATURES: LazyLock<IdempotencyStore> = LazyLock::new(|| {
+    let config = &GLOBAL_CONFIG;
+    IdempotencyStore::new(
+        Duration::from_millis(config.auth.max_skew * 2),
+        8,
+        config.idempotency.capacity,
+    )
+});
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// authorize returns the caller of a request allowed to `permission`. A
+// request carrying X-KSY-SIGNATURE is checked against its method, path, ids
+// and body, anything else needs an api key of the caller
+fn authorize<'a>(
+    config: &ApiAuth,
+    seen: &IdempotencyStore,
+    method: &str,
+    path: &str,
+    headers: &'a HeaderMap,
+    body: &[u8],
+    permission: Permission,
+) -> Result<&'a str, AppError> {
+    let caller_id = headers
+        .get(CALLER_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::Unauthorized(format!("{CALLER_ID_HEADER} is required")))?;
+    let unknown = || AppError::Unauthorized(format!("unknown caller {caller_id}"));
+    let caller = config.callers.get(caller_id).ok_or_else(unknown)?;
+
+    let signature = headers.get(SIGNATURE_HEADER).map(|value| value.as_bytes());
+    if signature.is_some() {
+        let max_skew = Duration::from_millis(config.max_skew);
+        let secret_of = |key_id: &str| caller.keys.get(key_id).map(|secret| secret.as_bytes());
+        sign::verify(method, path, headers, body, max_skew, secret_of)
+            .map_err(|err| AppError::Unauthorized(err.to_string()))?;
+    } else {
+        let api_key = headers
+            .get(API_KEY_HEADER)
+            .map(|value| value.as_bytes())
+            .ok_or_else(|| {
+                AppError::Unauthorized(format!(
+                    "{SIGNATURE_HEADER} or {API_KEY_HEADER} is required"
+                ))
+            })?;
+        if !caller
+            .api_keys
+            .iter()
+            .any(|key| constant_time_eq(key.as_bytes(), api_key))
+        {
+            return Err(AppError::Unauthorized("api key does not match".to_string()));
+        }
+    }
+
+    if !caller.permissions.contains(&permission) {
+        return Err(AppError::Forbidden(format!(
+            "caller {caller_id} may not call {permission:?}"
+        )));
+    }
+    // only requests that are let through are remembered, so a forged
+    // signature can not take the place of a real one
+    if let Some(signature) = signature {
+        match seen.try_add(String::from_utf8_lossy(signature).into_owned()) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(AppError::Unauthorized(
+                    "signature was already used".to_string(),
+                ))
+            }
+            Err(retry_after) => {
+                tracing::warn!("replay store is full, rejecting signed requests");
+                return Err(AppError::RateLimited(retry_after));
+            }
+        }
+    }
+    Ok(caller_id)
+}
+
+// authenticate is the middleware in front of every /onePass route, the
+// state is the permission the route needs
+pub async fn authenticate(
+    State(permission): State<Permission>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let config = &GLOBAL_CONFIG.auth;
+    if !config.enabled {
+        return Ok(next.run(request).await);
+    }
+    let (parts, body) = request.into_parts();
+    let request_id = parts
+        .headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let reject = |err: AppError| {
+        tracing::warn!("request {} rejected: {}", request_id, err);
+        err.with_request_id(&request_id)
+    };
+
+    // routes are nested under /onePass, the signature covers the full path
+    let path = match parts.extensions.get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path(),
+        None => parts.uri.path(),
+    };
+    let method = parts.method.as_str();
+    let body = if parts.headers.contains_key(SIGNATURE_HEADER) {
+        let bytes = to_bytes(body, MAX_BODY)
+            .await
+            .map_err(|err| reject(AppError::InvalidBody(err.to_string())))?;
+        authorize(
+            config,
+            &SEEN_SIGNATURES,
+            method,
+            path,
+            &parts.headers,
+            &bytes,
+            permission,
+        )
+        .map_err(reject)?;
+        Body::from(bytes)
+    } else {
+        authorize(
+            config,
+            &SEEN_SIGNATURES,
+            method,
+            path,
+            &parts.headers,
+            &[],
+            permission,
+        )
+        .map_err(reject)?;
+        body
+    };
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        config::Caller,
+        db::journal,
+        sign::{Signed, Signer},
+    };
+
+    fn config() -> ApiAuth {
+        let caller = |keys: &[(&str, &str)], api_keys: &[&str], permissions| Caller {
+            keys: keys
+                .iter()
+                .map(|(id, secret)| (id.to_string(), secret.to_string()))
+                .collect(),
+            api_keys: api_keys.iter().map(|key| key.to_string()).collect(),
+            permissions,
+        };
+        ApiAuth {
+            enabled: true,
+            max_skew: 300_000,
+            callers: HashMap::from([
+                (
+                    "20004".to_string(),
+                    caller(
+                        &[("k1", "secret"), ("k2", "rotated")],
+                        &[],
+                        vec![Permission::BatchPay, Permission::UserTrade],
+                    ),
+                ),
+                (
+                    "30001".to_string(),
+                    caller(&[], &["api-key"], vec![Permission::QueryUserAmount]),
+                ),
+            ]),
+        }
+    }
+
+    fn headers(caller_id: &str, extra: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CALLER_ID_HEADER, HeaderValue::from_str(caller_id).unwrap());
+        for (name, value) in extra {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    const PATH: &str = "/onePass/userTrade";
+
+    // sign returns the headers of caller_id sending body to PATH
+    fn sign(caller_id: &str, signer: &Signer, body: &[u8], timestamp: u64) -> HeaderMap {
+        let request = Signed {
+            method: "POST",
+            path: PATH,
+            caller_id,
+            request_id: "",
+            body,
+        };
+        headers(caller_id, &signer.headers_at(&request, timestamp))
+    }
+
+    fn check<'a>(
+        config: &ApiAuth,
+        seen: &IdempotencyStore,
+        headers: &'a HeaderMap,
+        body: &[u8],
+        permission: Permission,
+    ) -> Result<&'a str, AppError> {
+        authorize(config, seen, "POST", PATH, headers, body, permission)
+    }
+
+    #[test]
+    fn test_signed_requests() {
+        let (config, seen) = (
+            config(),
+            IdempotencyStore::new(Duration::from_secs(600), 8, 100),
+        );
+        let now = journal::now_millis();
+        let body = br#"{"sourceUid":1,"targetUid":2,"amount":1}"#;
+        let signed_by =
+            |caller_id, key_id, secret| sign(caller_id, &Signer::new(key_id, secret), body, now);
+        let signed = signed_by("20004", "k2", "rotated");
+        assert_eq!(
+            check(&config, &seen, &signed, body, Permission::UserTrade).unwrap(),
+            "20004"
+        );
+        // the same signature is not accepted twice
+        let err = check(&config, &seen, &signed, body, Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1007);
+
+        let forged = signed_by("20004", "k1", "guess");
+        let err = check(&config, &seen, &forged, body, Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1007);
+        let tampered = signed_by("20004", "k1", "secret");
+        let err = check(&config, &seen, &tampered, b"{}", Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1007);
+        // a signature is bound to the method and path it was made for
+        let moved = signed_by("20004", "k1", "secret");
+        for (method, path) in [("GET", PATH), ("POST", "/onePass/batchPay")] {
+            let err = authorize(
+                &config,
+                &seen,
+                method,
+                path,
+                &moved,
+                body,
+                Permission::BatchPay,
+            )
+            .unwrap_err();
+            assert_eq!(err.code(), 1007);
+        }
+        // keys belong to one caller
+        let other = signed_by("30001", "k1", "secret");
+        let err = check(&config, &seen, &other, body, Permission::QueryUserAmount).unwrap_err();
+        assert_eq!(err.code(), 1007);
+        let stale = sign("20004", &Signer::new("k1", "secret"), body, 1_000);
+        let err = check(&config, &seen, &stale, body, Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1007);
+
+        // a request the caller may not make does not use up its signature
+        let signed = signed_by("20004", "k1", "secret");
+        let err = check(&config, &seen, &signed, body, Permission::QueryUserAmount).unwrap_err();
+        assert_eq!(err.status(), axum::http::StatusCode::FORBIDDEN);
+        assert!(check(&config, &seen, &signed, body, Permission::BatchPay).is_ok());
+    }
+
+    #[test]
+    fn test_full_replay_store() {
+        let (config, seen) = (
+            config(),
+            IdempotencyStore::new(Duration::from_secs(600), 8, 1),
+        );
+        let now = journal::now_millis();
+        let body = b"{}";
+        let first = sign("20004", &Signer::new("k1", "secret"), body, now);
+        assert!(check(&config, &seen, &first, body, Permission::UserTrade).is_ok());
+        // the first signature is kept, the next one is turned away
+        let second = sign("20004", &Signer::new("k2", "rotated"), body, now);
+        let err = check(&config, &seen, &second, body, Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1009);
+        let err = check(&config, &seen, &first, body, Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1007);
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let (config, seen) = (
+            config(),
+            IdempotencyStore::new(Duration::from_secs(600), 8, 100),
+        );
+        let with_key = |caller_id, key: &str| headers(caller_id, &[(API_KEY_HEADER, key.into())]);
+
+        let valid = with_key("30001", "api-key");
+        assert!(check(&config, &seen, &valid, b"", Permission::QueryUserAmount).is_ok());
+        assert!(check(&config, &seen, &valid, b"", Permission::QueryUserAmount).is_ok());
+        let err = check(&config, &seen, &valid, b"", Permission::UserTrade).unwrap_err();
+        assert_eq!(err.code(), 1008);
+
+        for headers in [
+            with_key("30001", "api-kez"),
+            with_key("30001", "api-key-"),
+            // 20004 has no api keys, it has to sign
+            with_key("20004", "api-key"),
+            with_key("40000", "api-key"),
+            headers("30001", &[]),
+            HeaderMap::new(),
+        ] {
+            let err =
+                check(&config, &seen, &headers, b"", Permission::QueryUserAmount).unwrap_err();
+            assert_eq!(err.code(), 1007, "{err}");
+        }
+    }
+}
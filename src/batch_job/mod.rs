@@ -0,0 +1,426 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use awaitgroup::WaitGroup;
+use tokio::{
+    sync::Semaphore,
+    task,
+    time::{self, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        self,
+        account::AccountStatus,
+        batch::{BatchJob, JobState, JobUpdate, UidState},
+        error::{EngineError, Result},
+        idempotency::IdempotencyRecord,
+        journal,
+    },
+    drain::BoxFuture,
+    fund::{self, get_all_fund, Checkpoint},
+    limit,
+    retry::{self, BREAKERS},
+    upstream::FundProvider,
+    GLOBAL_CONFIG,
+};
+
+// JobCheckpoint records the get_pay transactions of one uid in its job,
+// settling a confirmed one credits it to the account
+struct JobCheckpoint {
+    batch_pay_id: String,
+    uid: i64,
+}
+
+impl Checkpoint for JobCheckpoint {
+    fn sending<'a>(
+        &'a self,
+        transaction_id: &'a str,
+        amount: i64,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        let step = JobUpdate::Sending {
+            uid: self.uid,
+            transaction_id: transaction_id.to_string(),
+            amount,
+        };
+        Box::pin(async move { Ok(db::api::update_job(&self.batch_pay_id, step).await?) })
+    }
+
+    fn unknown<'a>(&'a self, transaction_id: &'a str) -> BoxFuture<'a, ()> {
+        let step = JobUpdate::Unknown {
+            uid: self.uid,
+            transaction_id: transaction_id.to_string(),
+        };
+        Box::pin(async move {
+            update(&self.batch_pay_id, step).await;
+        })
+    }
+
+    // a settlement that can not be recorded stays in flight and is sent again on resume
+    fn settled<'a>(&'a self, transaction_id: &'a str, confirmed: bool) -> BoxFuture<'a, ()> {
+        let step = JobUpdate::Settled {
+            uid: self.uid,
+            transaction_id: transaction_id.to_string(),
+            confirmed,
+        };
+        Box::pin(async move {
+            update(&self.batch_pay_id, step).await;
+        })
+    }
+}
+
+async fn update(batch_pay_id: &str, update: JobUpdate) -> bool {
+    match db::api::update_job(batch_pay_id, update).await {
+        Ok(()) => true,
+        Err(err) => {
+            tracing::error!("batch pay {}: failed to update job: {}", batch_pay_id, err);
+            false
+        }
+    }
+}
+
+// start persists a new job for uids together with the batchPayId, nothing
+// is pulled from the upstream before this returns
+pub async fn start(batch_pay_id: &str, uids: &[i64], key: IdempotencyRecord) -> Result<()> {
+    let job = BatchJob::new(
+        batch_pay_id.to_string(),
+        uids,
+        Uuid::new_v4().to_string(),
+        journal::now_millis(),
+    );
+    db::api::start_job(job, key).await
+}
+
+pub fn get(batch_pay_id: &str) -> Option<BatchJob> {
+    db::api::job(batch_pay_id)
+}
+
+// resume_all picks up every job that did not deliver batch_pay_finish before the last shutdown
+pub fn resume_all(provider: &Arc<dyn FundProvider>) {
+    let jobs = db::api::unfinished_jobs();
+    if jobs.is_empty() {
+        return;
+    }
+    if GLOBAL_CONFIG.urls.get_pay.is_empty() {
+        tracing::warn!(
+            "{} unfinished batch pay jobs, but the fund service is not configured",
+            jobs.len()
+        );
+        return;
+    }
+    for job in jobs {
+        tracing::info!("resuming batch pay {}", job.batch_pay_id);
+        let provider = provider.clone();
+        task::spawn(async move {
+            let _permit = limit::wait_batch_job().await;
+            run(provider, job.batch_pay_id).await;
+        });
+    }
+}
+
+// run drives a job from wherever its checkpoints say it stopped to
+// exactly one acknowledged batch_pay_finish, or fails it once the retry
+// budget of the callback is used up
+pub async fn run(provider: Arc<dyn FundProvider>, batch_pay_id: String) {
+    let time_start = Instant::now();
+    let Some(job) = db::api::job(&batch_pay_id) else {
+        tracing::error!("batch pay {} does not exist", batch_pay_id);
+        return;
+    };
+    if job.state == JobState::Draining {
+        let job_slots = limit::job_in_flight();
+        let mut wg = WaitGroup::new();
+        for (uid, checkpoint) in job.uids {
+            if matches!(checkpoint.state, UidState::Done | UidState::Failed) {
+                continue;
+            }
+            let worker = wg.worker();
+            let (provider, batch_pay_id) = (provider.clone(), batch_pay_id.clone());
+            let job_slots = job_slots.clone();
+            let in_flight = checkpoint.in_flight.into_iter().collect();
+            task::spawn(async move {
+                drain(&provider, &batch_pay_id, uid, in_flight, job_slots).await;
+                worker.done();
+            });
+        }
+        wg.wait().await;
+        println!("pay_funds use time: {}", time_start.elapsed().as_secs_f64());
+        if !reconcile(&provider, &batch_pay_id, &job_slots).await {
+            return;
+        }
+        if !update(&batch_pay_id, JobUpdate::Finishing).await {
+            return;
+        }
+    }
+
+    // call batch_pay_finish when all user finish, every attempt carries
+    // the same request id so a repeat after a restart is recognizable
+    let result = retry::with_retry(
+        "batch_pay_finish",
+        &retry::policy(),
+        &BREAKERS.batch_pay_finish,
+        || async {
+            let finish = provider.batch_pay_finish(&job.finish_request_id, &batch_pay_id);
+            match tokio::time::timeout(Duration::from_millis(600), finish).await {
+                Ok(Ok(200)) => Ok(()),
+                Ok(Ok(code)) => Err(anyhow!("batch_pay_finish returned {}", code)),
+                Ok(Err(err)) => Err(err),
+                // 超时重试
+                Err(_) => Err(anyhow!("batch_pay_finish timed out")),
+            }
+        },
+    )
+    .await;
+    match result {
+        Ok(()) => {
+            update(&batch_pay_id, JobUpdate::Finished).await;
+            println!("use time: {}", time_start.elapsed().as_secs_f64());
+        }
+        Err(err) => {
+            tracing::error!("batch pay {} failed: {:#}", batch_pay_id, err);
+            update(
+                &batch_pay_id,
+                JobUpdate::Abort {
+                    error: format!("{err:#}"),
+                },
+            )
+            .await;
+        }
+    }
+}
+
+// reconcile sends every transaction still in flight again until each one
+// got an answer. Their outcome is unknown, so the job can not report how
+// much it collected before that, a confirmed one is credited as it settles.
+// After max_attempts rounds the job is aborted, so it gives back its
+// permit, and what is still unresolved stays in flight in its checkpoint
+// for an operator to settle
+async fn reconcile(
+    provider: &Arc<dyn FundProvider>,
+    batch_pay_id: &str,
+    job_slots: &Semaphore,
+) -> bool {
+    let policy = retry::policy();
+    let mut round = 0;
+    loop {
+        let Some(job) = db::api::job(batch_pay_id) else {
+            return false;
+        };
+        let unresolved = job.unresolved();
+        if unresolved.is_empty() {
+            return true;
+        }
+        if round == policy.max_attempts {
+            let ids: Vec<&str> = unresolved.iter().map(|(_, id, _)| id.as_str()).collect();
+            let error = format!(
+                "{} transactions unresolved after {} rounds: {}",
+                ids.len(),
+                round,
+                ids.join(", ")
+            );
+            tracing::error!("batch pay {}: {}", batch_pay_id, error);
+            update(batch_pay_id, JobUpdate::Abort { error }).await;
+            return false;
+        }
+        if round > 0 {
+            time::sleep(policy.max_delay).await;
+        }
+        round += 1;
+        tracing::warn!(
+            "batch pay {}: resolving {} transactions, round {}",
+            batch_pay_id,
+            unresolved.len(),
+            round
+        );
+        for (uid, transaction_id, amount) in unresolved {
+            let checkpoint = JobCheckpoint {
+                batch_pay_id: batch_pay_id.to_string(),
+                uid,
+            };
+            let code = fund::send_until_settled(
+                provider,
+                uid,
+                amount,
+                &transaction_id,
+                &checkpoint,
+                job_slots,
+            );
+            match code.await {
+                Ok(code) => checkpoint.settled(&transaction_id, code == 200).await,
+                Err(err) => tracing::warn!("batch pay {}: {:#}", batch_pay_id, err),
+            }
+        }
+    }
+}
+
+// drain settles the transactions a previous run left in flight and pulls
+// whatever is left for uid. Every confirmed get_pay is credited as it
+// settles, anything that could not be credited then is retried at the end
+async fn drain(
+    provider: &Arc<dyn FundProvider>,
+    batch_pay_id: &str,
+    uid: i64,
+    in_flight: Vec<(String, i64)>,
+    job_slots: Arc<Semaphore>,
+) {
+    if !update(batch_pay_id, JobUpdate::Draining { uid }).await {
+        return;
+    }
+    let checkpoint = Arc::new(JobCheckpoint {
+        batch_pay_id: batch_pay_id.to_string(),
+        uid,
+    });
+    // a uid whose retry budget runs out fails, its unanswered
+    // transactions stay in flight in the checkpoint
+    let drained = async {
+        for (transaction_id, amount) in in_flight {
+            let code = fund::send_until_settled(
+                provider,
+                uid,
+                amount,
+                &transaction_id,
+                &*checkpoint,
+                &job_slots,
+            )
+            .await?;
+            checkpoint.settled(&transaction_id, code == 200).await;
+        }
+        // money pulled for a closed account could not be credited anywhere,
+        // once uid is draining it can not be closed anymore
+        if let Ok(AccountStatus::Closed) = db::api::account_status(uid) {
+            return Err(EngineError::AccountClosed(uid).into());
+        }
+        get_all_fund(provider, uid, checkpoint, job_slots.clone()).await
+    };
+    if let Err(err) = drained.await {
+        update(
+            batch_pay_id,
+            JobUpdate::Failed {
+                uid,
+                error: format!("{err:#}"),
+            },
+        )
+        .await;
+        return;
+    }
+
+    let Some(checkpoint) = db::api::job(batch_pay_id).and_then(|job| job.uids.get(&uid).cloned())
+    else {
+        return;
+    };
+    let amount = checkpoint.collected - checkpoint.credited;
+    let result = match amount {
+        0 => db::api::open_account(uid).await,
+        _ => db::api::update_job(batch_pay_id, JobUpdate::Credit { uid, amount }).await,
+    };
+    match result {
+        Ok(()) => {
+            update(batch_pay_id, JobUpdate::Done { uid }).await;
+        }
+        Err(err) => {
+            tracing::error!("uid: {}, failed to add money {}: {}", uid, amount, err);
+            update(
+                batch_pay_id,
+                JobUpdate::Failed {
+                    uid,
+                    error: err.to_string(),
+                },
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::upstream::mock::MockProvider;
+
+    use super::*;
+
+    fn new_uids() -> [i64; 3] {
+        let base = (Uuid::new_v4().as_u128() % 1_000_000_000) as i64 * 10 + 1_000_000_000;
+        [base, base + 1, base + 2]
+    }
+
+    async fn start_job(uids: &[i64]) -> String {
+        let batch_pay_id = Uuid::new_v4().to_string();
+        let key = IdempotencyRecord::batch_pay(&batch_pay_id, journal::now_millis());
+        start(&batch_pay_id, uids, key).await.unwrap();
+        batch_pay_id
+    }
+
+    #[tokio::test]
+    async fn test_batch_pay_offline() {
+        let uids = new_uids();
+        let mock = Arc::new(
+            MockProvider::new()
+                .with_balance(uids[0], 8891)
+                .with_balance(uids[1], 1000093)
+                .with_delay(Duration::from_millis(2))
+                .with_timeouts(0.1)
+                .with_finish_failures(2),
+        );
+        let batch_pay_id = start_job(&uids).await;
+        run(mock.clone(), batch_pay_id.clone()).await;
+
+        let job = get(&batch_pay_id).unwrap();
+        assert_eq!(job.state, JobState::Finished);
+        assert!(job.failed().is_empty());
+        assert_eq!(job.collected(), 8891 + 1000093);
+        // uids[2] is unknown to the fund service and gets an empty account
+        for (uid, amount) in uids.into_iter().zip([8891, 1000093, 0]) {
+            assert_eq!(db::api::get_balance(uid).unwrap(), amount);
+            assert_eq!(job.uids[&uid].credited, amount);
+        }
+        assert_eq!(mock.balance(uids[0]), Some(0));
+        assert_eq!(
+            mock.finished(),
+            vec![(job.finish_request_id.clone(), batch_pay_id)]
+        );
+        assert!(mock.requests() > 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_pay_bounds_get_pay_per_job() {
+        // every uid is big enough for drain.parallel workers of its own
+        let uids = new_uids();
+        let balance = GLOBAL_CONFIG.drain.max_chunk * 300;
+        let mock = Arc::new(
+            MockProvider::new()
+                .with_balance(uids[0], balance)
+                .with_balance(uids[1], balance)
+                .with_balance(uids[2], balance)
+                .with_delay(Duration::from_millis(5)),
+        );
+        let batch_pay_id = start_job(&uids).await;
+        run(mock.clone(), batch_pay_id.clone()).await;
+
+        let job = get(&batch_pay_id).unwrap();
+        assert_eq!(job.state, JobState::Finished);
+        assert_eq!(job.collected(), balance * 3);
+        assert!(mock.peak_in_flight() <= GLOBAL_CONFIG.limits.job_in_flight as u64);
+    }
+
+    #[tokio::test]
+    async fn test_batch_pay_resolves_late_answers() {
+        let uids = new_uids();
+        let late_by = Duration::from_millis(GLOBAL_CONFIG.server.request_timeout as u64 + 200);
+        let mock = Arc::new(
+            MockProvider::new()
+                .with_balance(uids[0], 300)
+                .with_balance(uids[1], 1000)
+                .with_late_answers(0.1, late_by),
+        );
+        let batch_pay_id = start_job(&uids).await;
+        run(mock.clone(), batch_pay_id.clone()).await;
+
+        let job = get(&batch_pay_id).unwrap();
+        assert_eq!(job.state, JobState::Finished);
+        assert!(job.unresolved().is_empty());
+        assert!(job.uids.values().all(|c| c.unknown.is_empty()));
+        for (uid, amount) in uids.into_iter().zip([300, 1000, 0]) {
+            assert_eq!(db::api::get_balance(uid).unwrap(), amount);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// fake_upstream speaks the fund service protocol balance-api talks to, so
+// the whole service can be run and tested without the real one.
+//
+// Everything is configured through the environment:
+//   FAKE_UPSTREAM_ADDR            listen address, default 127.0.0.1:0
+//   FAKE_UPSTREAM_DELAY_MS        every request waits a random time up to this
+//   FAKE_UPSTREAM_ERROR_RATE      share of getPay answered 503, not applied
+//   FAKE_UPSTREAM_TIMEOUT_RATE    share of getPay applied but answered late
+//   FAKE_UPSTREAM_HANG_MS         how late, default 2000
+//   FAKE_UPSTREAM_FINISH_FAILURES the first n batchPayFinish are answered 500
+//   FAKE_UPSTREAM_KEYS            key_id:secret,... when set, POSTs must be
+//                                 signed with one of them or are answered 401
+//   FAKE_UPSTREAM_MAX_SKEW_MS     accepted clock skew of signatures, default 300000
+//
+// The bound address is printed as "listening on <addr>" once it accepts.
+use std::{
+    collections::HashMap,
+    env,
+    io::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use money::Money;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sim::Balances;
+use tokio::{net::TcpListener, time};
+
+#[path = "../money.rs"]
+#[allow(dead_code)]
+mod money;
+#[path = "../sign.rs"]
+#[allow(dead_code)]
+mod sign;
+#[path = "../upstream/sim.rs"]
+#[allow(dead_code)]
+mod sim;
+
+use sign::REQUEST_ID_HEADER;
+
+struct Faults {
+    delay: Duration,
+    error_rate: f64,
+    timeout_rate: f64,
+    hang: Duration,
+}
+
+struct Fake {
+    faults: Faults,
+    balances: Balances,
+    finished: Mutex<Vec<Finished>>,
+    finish_failures: AtomicU32,
+    get_pay_requests: AtomicU64,
+    // secrets by key id, no key means calls are not checked
+    keys: HashMap<String, Vec<u8>>,
+    max_skew: Duration,
+    unauthorized: AtomicU64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Fund {
+    uid: i64,
+    amount: Money,
+}
+
+#[derive(Deserialize)]
+struct GetPayJson {
+    #[serde(rename = "transactionId")]
+    transaction_id: String,
+    uid: i64,
+    amount: Money,
+}
+
+#[derive(Deserialize)]
+struct FinishJson {
+    #[serde(rename = "batchPayId")]
+    batch_pay_id: String,
+}
+
+#[derive(Clone, Serialize)]
+struct Finished {
+    #[serde(rename = "requestId")]
+    request_id: String,
+    #[serde(rename = "batchPayId")]
+    batch_pay_id: String,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} is not valid: {value}")),
+        Err(_) => default,
+    }
+}
+
+// keys parses FAKE_UPSTREAM_KEYS
+fn keys(value: &str) -> HashMap<String, Vec<u8>> {
+    value
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key_id, secret) = pair
+                .split_once(':')
+                .unwrap_or_else(|| panic!("FAKE_UPSTREAM_KEYS is not valid: {pair}"));
+            (key_id.to_string(), secret.as_bytes().to_vec())
+        })
+        .collect()
+}
+
+fn reply(code: i32, request_id: &str, msg: &str) -> Response {
+    Json(json!({"code": code, "requestId": request_id, "msg": msg, "data": ""})).into_response()
+}
+
+impl Fake {
+    // unauthorized answers a call whose signature does not verify, calls
+    // are only checked when keys are configured
+    fn unauthorized(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> Option<Response> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let secret_of = |key_id: &str| self.keys.get(key_id).map(Vec::as_slice);
+        match sign::verify(
+            method.as_str(),
+            uri.path(),
+            headers,
+            body.as_bytes(),
+            self.max_skew,
+            secret_of,
+        ) {
+            Ok(_) => None,
+            Err(err) => {
+                self.unauthorized.fetch_add(1, Ordering::Relaxed);
+                Some((StatusCode::UNAUTHORIZED, err.to_string()).into_response())
+            }
+        }
+    }
+}
+
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// get_pay echoes X-KSY-REQUEST-ID as requestId, balance-api drops answers
+// that do not carry its own id
+async fn get_pay(
+    State(fake): State<Arc<Fake>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    fake.get_pay_requests.fetch_add(1, Ordering::Relaxed);
+    if let Some(response) = fake.unauthorized(&method, &uri, &headers, &body) {
+        return response;
+    }
+    let Some(request_id) = request_id(&headers) else {
+        return (StatusCode::BAD_REQUEST, "missing X-KSY-REQUEST-ID").into_response();
+    };
+    let body: GetPayJson = match serde_json::from_str(&body) {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    sim::jitter(fake.faults.delay).await;
+    if sim::chance(fake.faults.error_rate) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "injected error").into_response();
+    }
+    let code = fake
+        .balances
+        .pay(body.uid, body.amount.cents(), &body.transaction_id);
+    if sim::chance(fake.faults.timeout_rate) {
+        time::sleep(fake.faults.hang).await;
+    }
+    reply(code, &request_id, "ok")
+}
+
+async fn init_funds(
+    State(fake): State<Arc<Fake>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if let Some(response) = fake.unauthorized(&method, &uri, &headers, &body) {
+        return response;
+    }
+    let funds: Vec<Fund> = match serde_json::from_str(&body) {
+        Ok(funds) => funds,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    sim::jitter(fake.faults.delay).await;
+    for fund in funds {
+        fake.balances.set(fund.uid, fund.amount.cents());
+    }
+    reply(200, "", "ok")
+}
+
+async fn batch_pay_finish(
+    State(fake): State<Arc<Fake>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if let Some(response) = fake.unauthorized(&method, &uri, &headers, &body) {
+        return response;
+    }
+    let Some(request_id) = request_id(&headers) else {
+        return (StatusCode::BAD_REQUEST, "missing X-KSY-REQUEST-ID").into_response();
+    };
+    let body: FinishJson = match serde_json::from_str(&body) {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    sim::jitter(fake.faults.delay).await;
+    if sim::fail_next(&fake.finish_failures) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "injected error").into_response();
+    }
+    fake.finished.lock().unwrap().push(Finished {
+        request_id: request_id.clone(),
+        batch_pay_id: body.batch_pay_id,
+    });
+    reply(200, &request_id, "ok")
+}
+
+// state lets tests look at what the fake saw
+async fn state(State(fake): State<Arc<Fake>>) -> Response {
+    let balances: Vec<Fund> = fake
+        .balances
+        .all()
+        .into_iter()
+        .map(|(uid, cents)| Fund {
+            uid,
+            amount: Money::from_cents(cents),
+        })
+        .collect();
+    Json(json!({
+        "balances": balances,
+        "finished": fake.finished.lock().unwrap().clone(),
+        "getPayRequests": fake.get_pay_requests.load(Ordering::Relaxed),
+        "unauthorized": fake.unauthorized.load(Ordering::Relaxed),
+    }))
+    .into_response()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let fake = Arc::new(Fake {
+        faults: Faults {
+            delay: Duration::from_millis(env_or("FAKE_UPSTREAM_DELAY_MS", 0)),
+            error_rate: env_or("FAKE_UPSTREAM_ERROR_RATE", 0.0),
+            timeout_rate: env_or("FAKE_UPSTREAM_TIMEOUT_RATE", 0.0),
+            hang: Duration::from_millis(env_or("FAKE_UPSTREAM_HANG_MS", 2000)),
+        },
+        balances: Balances::default(),
+        finished: Mutex::new(Vec::new()),
+        finish_failures: AtomicU32::new(env_or("FAKE_UPSTREAM_FINISH_FAILURES", 0)),
+        get_pay_requests: AtomicU64::new(0),
+        keys: keys(&env_or("FAKE_UPSTREAM_KEYS", String::new())),
+        max_skew: Duration::from_millis(env_or("FAKE_UPSTREAM_MAX_SKEW_MS", 300_000)),
+        unauthorized: AtomicU64::new(0),
+    });
+
+    let app = Router::new()
+        .route("/getPay", post(get_pay))
+        .route("/initFunds", post(init_funds))
+        .route("/batchPayFinish", post(batch_pay_finish))
+        .route("/state", get(state))
+        .with_state(fake);
+
+    let addr = env_or("FAKE_UPSTREAM_ADDR", "127.0.0.1:0".to_string());
+    let listener = TcpListener::bind(&addr).await?;
+    println!("listening on {}", listener.local_addr()?);
+    std::io::stdout().flush()?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
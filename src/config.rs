@@ -1,12 +1,21 @@
 use core::panic;
-use std::{fs::File, io::Read};
+use std::{collections::HashMap, env, fs::File, io::Read};
 
 use serde::Deserialize;
 
+use crate::{auth::Permission, drain::StrategyKind};
+
 #[derive(Deserialize)]
 pub struct Config {
     pub server: Server,
     pub urls: Urls,
+    pub db: Db,
+    pub idempotency: Idempotency,
+    pub headers: Headers,
+    pub auth: ApiAuth,
+    pub limits: Limits,
+    pub upstream: Upstream,
+    pub drain: Drain,
 }
 
 #[derive(Deserialize)]
@@ -23,9 +32,164 @@ pub struct Urls {
     pub batch_pay_finish: String,
 }
 
+#[derive(Deserialize)]
+pub struct Db {
+    pub engine: EngineKind,
+    pub data_dir: String,
+    // 快照间隔（秒），0 表示不做快照
+    pub snapshot_interval: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    // 仅内存，重启后数据丢失
+    Memory,
+    // 追加写日志并在启动时重放
+    Wal,
+}
+
+#[derive(Deserialize)]
+pub struct Idempotency {
+    // 去重保留时间（秒）
+    pub ttl: u64,
+    // 按时间分桶淘汰，桶越多淘汰越平滑
+    pub buckets: u32,
+    // 最多保留的 key 数量，超过后提前淘汰最老的桶
+    pub capacity: usize,
+}
+
+#[derive(Deserialize)]
+pub struct Headers {
+    pub missing_request_id: MissingRequestId,
+}
+
+// 请求没有带 X-KSY-REQUEST-ID 时的处理方式，userTrade 总是拒绝
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingRequestId {
+    Generate,
+    Reject,
+}
+
+// 入站请求的认证，关闭时任何人都可以调用所有接口
+#[derive(Deserialize)]
+pub struct ApiAuth {
+    pub enabled: bool,
+    // 签名时间戳允许的偏差（毫秒），窗口内同一个签名只接受一次
+    pub max_skew: u64,
+    // 以 X-KSY-KINGSTAR-ID 为 key
+    #[serde(default)]
+    pub callers: HashMap<String, Caller>,
+}
+
+// 调用方用自己的 key 签名，或者在 X-KSY-API-KEY 中带上静态密钥
+#[derive(Deserialize)]
+pub struct Caller {
+    // 签名用的 key id 到密钥，可以同时配置多个以便轮换
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    // 允许调用的接口
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+}
+
+// 超出限制的请求返回 429 并带上 Retry-After
+#[derive(Deserialize)]
+pub struct Limits {
+    // 每个 X-KSY-KINGSTAR-ID 的请求速率
+    pub per_caller: RateLimit,
+    // 每个 sourceUid 的 userTrade 速率
+    pub per_source_uid: RateLimit,
+    // 同时运行的 batch pay 任务数，0 表示不限制
+    pub batch_jobs: usize,
+    // 所有任务同时进行中的 get_pay 数，超出的排队等待，0 表示不限制
+    pub upstream_in_flight: usize,
+    // 单个任务同时进行中的 get_pay 数，由任务的所有 uid 共享，0 表示不限制
+    pub job_in_flight: usize,
+}
+
+// 令牌桶，rate 为每秒请求数，burst 为桶的容量，rate 为 0 表示不限制
+#[derive(Deserialize)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+#[derive(Deserialize)]
+pub struct Upstream {
+    pub retry: Retry,
+    pub breaker: Breaker,
+    pub http: Http,
+    pub auth: Auth,
+}
+
+// 上游调用的重试策略，时间单位均为毫秒
+#[derive(Deserialize, Clone)]
+pub struct Retry {
+    pub max_attempts: u32,
+    pub base_delay: u64,
+    pub max_delay: u64,
+    // 单次调用（含重试）的总时限
+    pub deadline: u64,
+}
+
+// 连续失败 failure_threshold 次后熔断 open_for 毫秒
+#[derive(Deserialize, Clone)]
+pub struct Breaker {
+    pub failure_threshold: u32,
+    pub open_for: u64,
+}
+
+// 上游共用的 HTTP 客户端，时间单位均为毫秒，0 表示不限制
+#[derive(Deserialize)]
+pub struct Http {
+    // 每个 host 保留的空闲连接数
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: u64,
+    pub tcp_keepalive: u64,
+    pub connect_timeout: u64,
+    pub read_timeout: u64,
+    pub version: HttpVersion,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    // https 时通过 ALPN 协商，http 时使用 HTTP/1.1
+    Auto,
+    Http1,
+    // 不协商直接使用 HTTP/2，要求上游支持 h2c
+    Http2,
+}
+
+// 调用上游时的身份，secret 不为空时所有请求都带 HMAC-SHA256 签名
+#[derive(Deserialize)]
+pub struct Auth {
+    // 作为 X-KSY-KINGSTAR-ID 发送
+    pub caller_id: String,
+    pub key_id: String,
+    pub secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct Drain {
+    pub strategy: StrategyKind,
+    // 每个 uid 同时发出的 get_pay 数
+    pub parallel: usize,
+    // 单次 get_pay 的最大金额（分）
+    pub max_chunk: i64,
+}
+
+// 配置文件路径，默认为当前目录下的 config.yaml
+pub const CONFIG_PATH_ENV: &str = "BALANCE_API_CONFIG";
+
 impl Config {
     pub fn load_config() -> Self {
-        match File::open("config.yaml") {
+        let path = env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| "config.yaml".to_string());
+        match File::open(path) {
             Ok(mut yaml_file) => {
                 let mut buf = String::new();
                 yaml_file.read_to_string(&mut buf).expect("Failed to read to string.");
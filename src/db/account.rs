@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::{EngineError, Result},
+    journal::EntryKind,
+};
+
+// AccountStatus is where an account is in its lifecycle, active and frozen
+// can be switched back and forth, closed is final
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AccountStatus {
+    Active,
+    // money still comes in, trades out of the account are rejected
+    Frozen,
+    // every change is rejected, the balance was zero or swept out on close
+    Closed,
+}
+
+impl AccountStatus {
+    // check_posting tells whether an account in this status takes a change of `kind`
+    pub fn check_posting(self, uid: i64, kind: EntryKind) -> Result<()> {
+        match (self, kind) {
+            (AccountStatus::Closed, _) => Err(EngineError::AccountClosed(uid)),
+            (AccountStatus::Frozen, EntryKind::TradeDebit) => Err(EngineError::AccountFrozen(uid)),
+            _ => Ok(()),
+        }
+    }
+
+    // check_change tells whether an account in this status may be frozen,
+    // unfrozen or closed
+    pub fn check_change(self, uid: i64) -> Result<()> {
+        match self {
+            AccountStatus::Closed => Err(EngineError::AccountClosed(uid)),
+            _ => Ok(()),
+        }
+    }
+}
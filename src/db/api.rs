@@ -1,26 +1,176 @@
-use std::sync::{Arc, LazyLock};
+use std::{
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
-use anyhow::Result;
+use crate::{config::EngineKind, GLOBAL_CONFIG};
 
-use super::mmap::MMap;
+use super::{
+    account::AccountStatus,
+    batch::{BatchJob, JobUpdate},
+    error::{EngineError, Result},
+    idempotency::{IdempotencyRecord, KeyScope},
+    journal::{self, HistoryPage},
+    mmap::MMap,
+    wal::Wal,
+};
 
 pub trait Engine: Send + Sync {
-    fn add_money(&self, uid: i64, amount: i64);
+    // open_account creates an empty account, it is a no-op if uid already
+    // exists, a closed uid can not be opened again
+    fn open_account(&self, uid: i64) -> Result<()>;
+    fn account_status(&self, uid: i64) -> Result<AccountStatus>;
+    // freeze_account stops uid from trading money out, it can still receive
+    fn freeze_account(&self, uid: i64) -> Result<()>;
+    fn unfreeze_account(&self, uid: i64) -> Result<()>;
+    // close_account closes uid for good, a balance left in it is swept to
+    // `sweep_to` in the same change, without a sweep target it must be empty.
+    // It fails while a running batch pay is draining uid
+    fn close_account(&self, uid: i64, sweep_to: Option<i64>) -> Result<()>;
     fn get_balance(&self, uid: i64) -> Result<i64>;
     fn transfer(&self, from: i64, to: i64, amount: i64) -> Result<()>;
+    // transfer_once transfers and remembers `key` as one durable change, so
+    // after a crash a trade is either both applied and known, or neither
+    fn transfer_once(&self, from: i64, to: i64, amount: i64, key: IdempotencyRecord) -> Result<()> {
+        self.transfer(from, to, amount)?;
+        self.remember(key)
+    }
+    // remember stores or updates an idempotency key
+    fn remember(&self, key: IdempotencyRecord) -> Result<()>;
+    // idempotency_keys returns all keys first seen at or after `since` (unix millis)
+    fn idempotency_keys(&self, since: u64) -> Vec<IdempotencyRecord>;
+    // idempotency_key looks up a single key that has not expired yet
+    fn idempotency_key(&self, scope: KeyScope, key: &str) -> Option<IdempotencyRecord>;
+    // expire_keys forgets keys first seen before `before` (unix millis)
+    fn expire_keys(&self, before: u64);
+    // start_job stores a new batch pay job together with its idempotency key
+    fn start_job(&self, job: BatchJob, key: IdempotencyRecord) -> Result<()>;
+    // update_job applies one step of a batch pay job, a credit step moves
+    // the money and records it in the job as one durable change
+    fn update_job(&self, batch_pay_id: &str, update: JobUpdate) -> Result<()>;
+    fn job(&self, batch_pay_id: &str) -> Option<BatchJob>;
+    // unfinished_jobs returns the jobs that neither delivered batch_pay_finish nor gave up
+    fn unfinished_jobs(&self) -> Vec<BatchJob>;
+    // expire_jobs forgets jobs finished before `before` (unix millis)
+    fn expire_jobs(&self, before: u64);
+    // history pages through the journal of uid, newest entry first
+    fn history(&self, uid: i64, offset: usize, limit: usize) -> Result<HistoryPage>;
+    // verify_ledger checks that the double-entry ledger sums to zero
+    fn verify_ledger(&self) -> Result<()>;
+    // snapshot persists the current state, engines without storage do nothing
+    fn snapshot(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
-pub static MY_ENGINE: LazyLock<Arc<dyn Engine>> =
-    LazyLock::new(|| Arc::new(MMap::new()));
+// 出现错误直接 panic!
+pub static MY_ENGINE: LazyLock<Arc<dyn Engine>> = LazyLock::new(|| {
+    let config = &GLOBAL_CONFIG.db;
+    match config.engine {
+        EngineKind::Memory => Arc::new(MMap::new()),
+        EngineKind::Wal => Arc::new(Wal::open(data_dir()).expect("Failed to open log")),
+    }
+});
 
-pub fn add_money(uid: i64, amount: i64) {
-    MY_ENGINE.add_money(uid, amount)
+fn data_dir() -> PathBuf {
+    // unit tests get a log of their own, they never touch the configured data dir
+    if cfg!(test) {
+        return std::env::temp_dir().join(format!("balance-test-{}", uuid::Uuid::new_v4()));
+    }
+    PathBuf::from(&GLOBAL_CONFIG.db.data_dir)
+}
+
+// blocking runs a mutation on the blocking pool, a durable engine waits
+// for an fsync before it returns and must not hold up a runtime worker
+async fn blocking(mutation: impl FnOnce(&dyn Engine) -> Result<()> + Send + 'static) -> Result<()> {
+    tokio::task::spawn_blocking(move || mutation(&**MY_ENGINE))
+        .await
+        .map_err(|err| EngineError::Storage(err.into()))?
+}
+
+pub async fn open_account(uid: i64) -> Result<()> {
+    blocking(move |engine| engine.open_account(uid)).await
+}
+
+pub fn account_status(uid: i64) -> Result<AccountStatus> {
+    MY_ENGINE.account_status(uid)
+}
+
+pub async fn freeze_account(uid: i64) -> Result<()> {
+    blocking(move |engine| engine.freeze_account(uid)).await
+}
+
+pub async fn unfreeze_account(uid: i64) -> Result<()> {
+    blocking(move |engine| engine.unfreeze_account(uid)).await
+}
+
+pub async fn close_account(uid: i64, sweep_to: Option<i64>) -> Result<()> {
+    blocking(move |engine| engine.close_account(uid, sweep_to)).await
 }
 
 pub fn get_balance(uid: i64) -> Result<i64> {
     MY_ENGINE.get_balance(uid)
 }
 
-pub fn transfer(from: i64, to: i64, amount: i64) -> Result<()> {
-    MY_ENGINE.transfer(from, to, amount)
+pub async fn transfer_once(from: i64, to: i64, amount: i64, key: IdempotencyRecord) -> Result<()> {
+    blocking(move |engine| engine.transfer_once(from, to, amount, key)).await
+}
+
+pub async fn remember(key: IdempotencyRecord) -> Result<()> {
+    blocking(move |engine| engine.remember(key)).await
+}
+
+pub fn idempotency_keys(since: u64) -> Vec<IdempotencyRecord> {
+    MY_ENGINE.idempotency_keys(since)
+}
+
+pub fn idempotency_key(scope: KeyScope, key: &str) -> Option<IdempotencyRecord> {
+    MY_ENGINE.idempotency_key(scope, key)
+}
+
+pub async fn start_job(job: BatchJob, key: IdempotencyRecord) -> Result<()> {
+    blocking(move |engine| engine.start_job(job, key)).await
+}
+
+pub async fn update_job(batch_pay_id: &str, update: JobUpdate) -> Result<()> {
+    let batch_pay_id = batch_pay_id.to_string();
+    blocking(move |engine| engine.update_job(&batch_pay_id, update)).await
+}
+
+pub fn job(batch_pay_id: &str) -> Option<BatchJob> {
+    MY_ENGINE.job(batch_pay_id)
+}
+
+pub fn unfinished_jobs() -> Vec<BatchJob> {
+    MY_ENGINE.unfinished_jobs()
+}
+
+pub fn history(uid: i64, offset: usize, limit: usize) -> Result<HistoryPage> {
+    MY_ENGINE.history(uid, offset, limit)
+}
+
+pub fn verify_ledger() -> Result<()> {
+    MY_ENGINE.verify_ledger()
+}
+
+// run_snapshots takes a snapshot every `interval` until the process exits,
+// idempotency keys and finished jobs older than `key_ttl` are left out of it
+pub async fn run_snapshots(interval: Duration, key_ttl: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let before = journal::now_millis().saturating_sub(key_ttl.as_millis() as u64);
+        let result = tokio::task::spawn_blocking(move || {
+            MY_ENGINE.expire_keys(before);
+            MY_ENGINE.expire_jobs(before);
+            MY_ENGINE.snapshot()
+        });
+        match result.await {
+            Ok(Err(err)) => tracing::error!("snapshot failed: {}", err),
+            Err(err) => tracing::error!("snapshot task panicked: {}", err),
+            Ok(Ok(())) => {}
+        }
+    }
 }
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{EngineError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UidState {
+    Pending,
+    Draining,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    // uids are being drained from the upstream
+    Draining,
+    // every uid is done or failed, batch_pay_finish is being delivered
+    Finishing,
+    Finished,
+    // batch_pay_finish could not be delivered within the retry budget
+    Failed,
+}
+
+// UidCheckpoint is how far the drain of one uid got, enough to pick it up
+// again after a crash without losing or double counting a get_pay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UidCheckpoint {
+    pub state: UidState,
+    // cents the upstream confirmed for this uid
+    pub collected: i64,
+    // cents of `collected` already credited to the account
+    pub credited: i64,
+    // get_pay transactions sent but not answered yet, transactionId -> cents
+    #[serde(default)]
+    pub in_flight: BTreeMap<String, i64>,
+    // in flight transactions that timed out, the upstream may or may not
+    // have paid them until the same transactionId gets an answer
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub unknown: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchJob {
+    pub batch_pay_id: String,
+    // X-KSY-REQUEST-ID of the batch_pay_finish callback, the same on every
+    // attempt so the upstream can drop the ones it already handled
+    pub finish_request_id: String,
+    pub state: JobState,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(with = "uid_pairs")]
+    pub uids: BTreeMap<i64, UidCheckpoint>,
+}
+
+// uids are stored as [uid, checkpoint] pairs, integer map keys do not
+// survive the flattened log records
+mod uid_pairs {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::UidCheckpoint;
+
+    pub fn serialize<S: Serializer>(
+        uids: &BTreeMap<i64, UidCheckpoint>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(uids)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<i64, UidCheckpoint>, D::Error> {
+        let pairs = Vec::<(i64, UidCheckpoint)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+// JobUpdate is one step of a batch pay job, the engine applies and
+// persists them one at a time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum JobUpdate {
+    Draining {
+        uid: i64,
+    },
+    Sending {
+        uid: i64,
+        transaction_id: String,
+        amount: i64,
+    },
+    // transaction_id got no answer in time, its outcome is unknown
+    Unknown {
+        uid: i64,
+        transaction_id: String,
+    },
+    // the upstream answered transaction_id for good, confirmed is true on 200
+    // and credits the amount to the account in the same step
+    Settled {
+        uid: i64,
+        transaction_id: String,
+        confirmed: bool,
+    },
+    // credits collected cents to the account in the same step, confirmed
+    // get_pay transactions are credited as part of Settled already
+    Credit {
+        uid: i64,
+        amount: i64,
+    },
+    Done {
+        uid: i64,
+    },
+    Failed {
+        uid: i64,
+        error: String,
+    },
+    Finishing,
+    Finished,
+    // gives up on the whole job, it is not resumed after a restart
+    Abort {
+        error: String,
+    },
+}
+
+impl BatchJob {
+    pub fn new(batch_pay_id: String, uids: &[i64], finish_request_id: String, now: u64) -> Self {
+        let uids = uids
+            .iter()
+            .map(|uid| {
+                let checkpoint = UidCheckpoint {
+                    state: UidState::Pending,
+                    collected: 0,
+                    credited: 0,
+                    in_flight: BTreeMap::new(),
+                    unknown: BTreeSet::new(),
+                    error: None,
+                };
+                (*uid, checkpoint)
+            })
+            .collect();
+        BatchJob {
+            batch_pay_id,
+            finish_request_id,
+            state: JobState::Draining,
+            created_at: now,
+            finished_at: None,
+            error: None,
+            uids,
+        }
+    }
+
+    // is_running is true until batch_pay_finish was delivered or the job was given up
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, JobState::Draining | JobState::Finishing)
+    }
+
+    // is_draining is true while the job may still credit money to uid: it
+    // is draining it, or a get_pay of it is unanswered, even in a job that
+    // was given up
+    pub fn is_draining(&self, uid: i64) -> bool {
+        self.uids.get(&uid).is_some_and(|c| {
+            (self.is_running() && c.state == UidState::Draining) || !c.in_flight.is_empty()
+        })
+    }
+
+    pub fn collected(&self) -> i64 {
+        self.uids.values().map(|c| c.collected).sum()
+    }
+
+    pub fn failed(&self) -> Vec<i64> {
+        self.uids
+            .iter()
+            .filter(|(_, c)| c.state == UidState::Failed)
+            .map(|(uid, _)| *uid)
+            .collect()
+    }
+
+    fn uid(update: &JobUpdate) -> Option<i64> {
+        match update {
+            JobUpdate::Draining { uid }
+            | JobUpdate::Sending { uid, .. }
+            | JobUpdate::Unknown { uid, .. }
+            | JobUpdate::Settled { uid, .. }
+            | JobUpdate::Credit { uid, .. }
+            | JobUpdate::Done { uid }
+            | JobUpdate::Failed { uid, .. } => Some(*uid),
+            JobUpdate::Finishing | JobUpdate::Finished | JobUpdate::Abort { .. } => None,
+        }
+    }
+
+    // unresolved lists every get_pay still in flight as (uid, transactionId, cents)
+    pub fn unresolved(&self) -> Vec<(i64, String, i64)> {
+        self.uids
+            .iter()
+            .flat_map(|(uid, c)| {
+                c.in_flight
+                    .iter()
+                    .map(|(tid, amount)| (*uid, tid.clone(), *amount))
+            })
+            .collect()
+    }
+
+    // in_flight returns the amount of a get_pay of uid that is still unanswered
+    pub fn in_flight(&self, uid: i64, transaction_id: &str) -> Option<i64> {
+        self.uids.get(&uid)?.in_flight.get(transaction_id).copied()
+    }
+
+    // check validates an update against the job, the account side of a
+    // credit is checked by the engine
+    pub fn check(&self, update: &JobUpdate) -> Result<()> {
+        let Some(uid) = Self::uid(update) else {
+            return Ok(());
+        };
+        let checkpoint = self.uids.get(&uid).ok_or_else(|| {
+            EngineError::Corrupted(format!(
+                "uid {uid} is not part of batch pay {}",
+                self.batch_pay_id
+            ))
+        })?;
+        if let JobUpdate::Credit { amount, .. } = update {
+            if *amount > checkpoint.collected - checkpoint.credited {
+                return Err(EngineError::InvalidAmount(*amount));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, update: &JobUpdate, timestamp: u64) {
+        match update {
+            JobUpdate::Finishing => self.state = JobState::Finishing,
+            JobUpdate::Finished => {
+                self.state = JobState::Finished;
+                self.finished_at = Some(timestamp);
+            }
+            JobUpdate::Abort { error } => {
+                self.state = JobState::Failed;
+                self.finished_at = Some(timestamp);
+                self.error = Some(error.clone());
+            }
+            _ => {}
+        }
+        let Some(checkpoint) = Self::uid(update).and_then(|uid| self.uids.get_mut(&uid)) else {
+            return;
+        };
+        match update {
+            JobUpdate::Draining { .. } => checkpoint.state = UidState::Draining,
+            JobUpdate::Sending {
+                transaction_id,
+                amount,
+                ..
+            } => {
+                checkpoint.in_flight.insert(transaction_id.clone(), *amount);
+            }
+            JobUpdate::Unknown { transaction_id, .. } => {
+                if checkpoint.in_flight.contains_key(transaction_id) {
+                    checkpoint.unknown.insert(transaction_id.clone());
+                }
+            }
+            JobUpdate::Settled {
+                transaction_id,
+                confirmed,
+                ..
+            } => {
+                // settling twice is a no-op, so a replayed answer is never counted again
+                checkpoint.unknown.remove(transaction_id);
+                if let Some(amount) = checkpoint.in_flight.remove(transaction_id) {
+                    if *confirmed {
+                        checkpoint.collected = checkpoint.collected.saturating_add(amount);
+                    }
+                }
+            }
+            JobUpdate::Credit { amount, .. } => checkpoint.credited += amount,
+            JobUpdate::Done { .. } => checkpoint.state = UidState::Done,
+            JobUpdate::Failed { error, .. } => {
+                checkpoint.state = UidState::Failed;
+                checkpoint.error = Some(error.clone());
+            }
+            JobUpdate::Finishing | JobUpdate::Finished | JobUpdate::Abort { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_updates() {
+        let mut job = BatchJob::new("job-1".to_string(), &[1, 2], "finish".to_string(), 10);
+        let sending = |tid: &str, amount| JobUpdate::Sending {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            amount,
+        };
+        let settled = |tid: &str, confirmed| JobUpdate::Settled {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            confirmed,
+        };
+        job.apply(&JobUpdate::Draining { uid: 1 }, 11);
+        job.apply(&sending("a", 100), 11);
+        job.apply(&sending("b", 50), 11);
+        job.apply(&sending("c", 25), 11);
+        let unknown = |tid: &str| JobUpdate::Unknown {
+            uid: 1,
+            transaction_id: tid.to_string(),
+        };
+        job.apply(&unknown("a"), 12);
+        job.apply(&unknown("c"), 12);
+        job.apply(&unknown("z"), 12);
+        job.apply(&settled("a", true), 12);
+        job.apply(&settled("a", true), 12);
+        job.apply(&settled("b", false), 12);
+        let checkpoint = &job.uids[&1];
+        assert_eq!(checkpoint.state, UidState::Draining);
+        assert_eq!(checkpoint.collected, 100);
+        assert_eq!(checkpoint.in_flight.keys().collect::<Vec<_>>(), ["c"]);
+        assert_eq!(checkpoint.unknown.iter().collect::<Vec<_>>(), ["c"]);
+        assert_eq!(job.unresolved(), vec![(1, "c".to_string(), 25)]);
+
+        assert!(job
+            .check(&JobUpdate::Credit {
+                uid: 1,
+                amount: 101
+            })
+            .is_err());
+        assert!(job.check(&JobUpdate::Draining { uid: 3 }).is_err());
+        job.check(&JobUpdate::Credit {
+            uid: 1,
+            amount: 100,
+        })
+        .unwrap();
+        job.apply(
+            &JobUpdate::Credit {
+                uid: 1,
+                amount: 100,
+            },
+            13,
+        );
+        assert!(job.check(&JobUpdate::Credit { uid: 1, amount: 1 }).is_err());
+
+        job.apply(
+            &JobUpdate::Failed {
+                uid: 2,
+                error: "boom".to_string(),
+            },
+            13,
+        );
+        job.apply(&JobUpdate::Finished, 14);
+        assert_eq!(job.failed(), vec![2]);
+        assert_eq!(job.state, JobState::Finished);
+        assert_eq!(job.finished_at, Some(14));
+        assert!(!job.is_running());
+
+        let mut job = BatchJob::new("job-2".to_string(), &[1], "finish".to_string(), 10);
+        assert!(job.is_running());
+        job.apply(
+            &JobUpdate::Abort {
+                error: "gave up".to_string(),
+            },
+            11,
+        );
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.error.as_deref(), Some("gave up"));
+        assert!(!job.is_running());
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, EngineError>;
+
+#[derive(Debug)]
+pub enum EngineError {
+    AccountNotFound(i64),
+    // the account may receive money, but not send it
+    AccountFrozen(i64),
+    // the account does not take any change anymore
+    AccountClosed(i64),
+    // an account with money left can only be closed into another one
+    BalanceNotZero(i64),
+    // a batch pay is still pulling money for the account
+    AccountDraining(i64),
+    InsufficientBalance,
+    // amounts must be positive
+    InvalidAmount(i64),
+    // the change would overflow the balance of an account
+    Overflow(i64),
+    SelfTransfer,
+    SystemAccount(i64),
+    JobNotFound(String),
+    // the postings of a transaction do not sum to zero
+    Unbalanced,
+    // the ledger invariant does not hold anymore
+    Corrupted(String),
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::AccountNotFound(uid) => write!(f, "can not find the account {uid}"),
+            EngineError::AccountFrozen(uid) => write!(f, "account {uid} is frozen"),
+            EngineError::AccountClosed(uid) => write!(f, "account {uid} is closed"),
+            EngineError::BalanceNotZero(uid) => write!(f, "account {uid} still has a balance"),
+            EngineError::AccountDraining(uid) => {
+                write!(f, "a batch pay is still draining account {uid}")
+            }
+            EngineError::InsufficientBalance => write!(f, "insufficient balance"),
+            EngineError::InvalidAmount(amount) => {
+                write!(f, "amount must be positive, got {amount}")
+            }
+            EngineError::Overflow(uid) => write!(f, "balance of account {uid} would overflow"),
+            EngineError::SelfTransfer => write!(f, "can not transfer to the same account"),
+            EngineError::SystemAccount(uid) => write!(f, "account {uid} is a system account"),
+            EngineError::JobNotFound(id) => write!(f, "can not find the batch pay {id}"),
+            EngineError::Unbalanced => write!(f, "unbalanced transaction"),
+            EngineError::Corrupted(msg) => write!(f, "ledger is corrupted: {msg}"),
+            EngineError::Storage(err) => write!(f, "storage error: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<anyhow::Error> for EngineError {
+    fn from(err: anyhow::Error) -> Self {
+        EngineError::Storage(err)
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyScope {
+    BatchPay,
+    Trade,
+}
+
+// StoredResponse is the outcome of the first request with a key,
+// retries get exactly this response back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+// IdempotencyRecord is an idempotency key as kept by the engine, so
+// deduplication still holds after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub scope: KeyScope,
+    pub key: String,
+    pub fingerprint: u64,
+    // unix timestamp in milliseconds of the first request
+    pub timestamp: u64,
+    pub outcome: Option<StoredResponse>,
+}
+
+impl IdempotencyRecord {
+    // batch_pay is the key of a new batch pay, batchPayIds are not compared
+    // by body and the job itself is their outcome
+    pub fn batch_pay(key: &str, timestamp: u64) -> Self {
+        IdempotencyRecord {
+            scope: KeyScope::BatchPay,
+            key: key.to_string(),
+            fingerprint: 0,
+            timestamp,
+            outcome: None,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryKind {
+    // money pulled in from the upstream fund by batch pay
+    BatchPayCredit,
+    TradeDebit,
+    TradeCredit,
+    // the balance left in an account that is closed, and where it went
+    SweepDebit,
+    SweepCredit,
+}
+
+// JournalEntry records a single balance change of one account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    // all entries of the same double-entry transaction share this id
+    pub transaction_id: u64,
+    // unix timestamp in milliseconds
+    pub timestamp: u64,
+    pub kind: EntryKind,
+    pub counterparty: Option<i64>,
+    // signed change in cents, negative for debits
+    pub amount: i64,
+    // balance right after this entry
+    pub balance: i64,
+}
+
+pub struct HistoryPage {
+    pub total: usize,
+    pub entries: Vec<JournalEntry>,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// page returns `limit` entries starting at `offset`, newest first
+pub fn page(journal: &[JournalEntry], offset: usize, limit: usize) -> HistoryPage {
+    HistoryPage {
+        total: journal.len(),
+        entries: journal
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect(),
+    }
+}
@@ -0,0 +1,70 @@
+use super::journal::EntryKind;
+
+// system account that is debited for every batch pay credit, it stands for
+// the money held by the upstream fund service and is the only account
+// allowed to go negative. Every credit touches it, so it is kept as a
+// single counter without a journal of its own
+pub const UPSTREAM_FUND_UID: i64 = -1;
+
+pub fn is_system_account(uid: i64) -> bool {
+    uid == UPSTREAM_FUND_UID
+}
+
+// Posting is one leg of a transaction, the postings of a transaction must sum to zero
+#[derive(Debug, Clone, Copy)]
+pub struct Posting {
+    pub uid: i64,
+    pub amount: i64,
+    pub kind: EntryKind,
+    pub counterparty: i64,
+}
+
+// batch_pay is the user side of a batch pay credit, the other side is
+// debited from the upstream counter
+pub fn batch_pay(uid: i64, amount: i64) -> Posting {
+    Posting {
+        uid,
+        amount,
+        kind: EntryKind::BatchPayCredit,
+        counterparty: UPSTREAM_FUND_UID,
+    }
+}
+
+pub fn trade(from: i64, to: i64, amount: i64) -> [Posting; 2] {
+    [
+        Posting {
+            uid: from,
+            amount: -amount,
+            kind: EntryKind::TradeDebit,
+            counterparty: to,
+        },
+        Posting {
+            uid: to,
+            amount,
+            kind: EntryKind::TradeCredit,
+            counterparty: from,
+        },
+    ]
+}
+
+// sweep moves everything left in a closing account to another one
+pub fn sweep(from: i64, to: i64, amount: i64) -> [Posting; 2] {
+    [
+        Posting {
+            uid: from,
+            amount: -amount,
+            kind: EntryKind::SweepDebit,
+            counterparty: to,
+        },
+        Posting {
+            uid: to,
+            amount,
+            kind: EntryKind::SweepCredit,
+            counterparty: from,
+        },
+    ]
+}
+
+pub fn is_balanced(postings: &[Posting]) -> bool {
+    postings.iter().map(|p| p.amount as i128).sum::<i128>() == 0
+}
@@ -1,65 +1,966 @@
-use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+};
+
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 
-use super::api::Engine;
+use super::{
+    account::AccountStatus,
+    api::Engine,
+    batch::{BatchJob, JobUpdate},
+    error::{EngineError, Result},
+    idempotency::{IdempotencyRecord, KeyScope},
+    journal::{self, HistoryPage, JournalEntry},
+    ledger::{self, Posting},
+};
 
-struct BalanceAccount {
+#[derive(Serialize, Deserialize)]
+pub struct BalanceAccount {
     uid: i64,
     balance: i64,
+    #[serde(default)]
+    journal: Vec<JournalEntry>,
+    status: AccountStatus,
+}
+
+impl BalanceAccount {
+    fn new(uid: i64) -> Self {
+        BalanceAccount {
+            uid,
+            balance: 0,
+            journal: vec![],
+            status: AccountStatus::Active,
+        }
+    }
+
+    fn record(&mut self, id: u64, transaction_id: u64, timestamp: u64, posting: &Posting) {
+        self.balance += posting.amount;
+        self.journal.push(JournalEntry {
+            id,
+            transaction_id,
+            timestamp,
+            kind: posting.kind,
+            counterparty: Some(posting.counterparty),
+            amount: posting.amount,
+            balance: self.balance,
+        });
+    }
 }
 
+type Account = Arc<Mutex<BalanceAccount>>;
+type Guard<'a> = MutexGuard<'a, BalanceAccount>;
+
+// every account has its own lock, the DashMap shard lock is only held
+// while looking the account up, so two accounts never wait on the same shard.
+// The upstream system account is a plain counter, credits to different
+// accounts do not wait on it
 pub struct MMap {
-    uid_map: DashMap<i64, BalanceAccount>,
+    uid_map: DashMap<i64, Account>,
+    upstream_balance: AtomicI64,
+    keys: DashMap<(KeyScope, String), IdempotencyRecord>,
+    jobs: DashMap<String, BatchJob>,
+    // closing an account and starting to drain one exclude each other, so a
+    // drain never pulls money for an account that is closed under it
+    closing: RwLock<()>,
+    next_entry_id: AtomicU64,
+    next_transaction_id: AtomicU64,
+}
+
+fn lock(account: &Account) -> Guard<'_> {
+    account.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 impl MMap {
     pub fn new() -> Self {
         MMap {
             uid_map: DashMap::new(),
+            upstream_balance: AtomicI64::new(0),
+            keys: DashMap::new(),
+            jobs: DashMap::new(),
+            closing: RwLock::new(()),
+            next_entry_id: AtomicU64::new(1),
+            next_transaction_id: AtomicU64::new(1),
         }
     }
-}
 
-impl Engine for MMap {
-    // add_money will add balance to uid account
-    // if account do not exist, then just add a new one
-    fn add_money(&self, uid: i64, amount: i64) {
-        if let Some(mut account) = self.uid_map.get_mut(&uid) {
-            account.balance += amount;
-        } else {
-            let account = BalanceAccount {
+    fn account(&self, uid: i64) -> Result<Account> {
+        self.uid_map
+            .get(&uid)
+            .map(|account| account.clone())
+            .ok_or(EngineError::AccountNotFound(uid))
+    }
+
+    fn account_or_create(&self, uid: i64) -> Account {
+        self.uid_map
+            .entry(uid)
+            .or_insert_with(|| Arc::new(Mutex::new(BalanceAccount::new(uid))))
+            .clone()
+    }
+
+    // dump copies all accounts, callers must block writers for a consistent view
+    pub fn dump(&self) -> Vec<BalanceAccount> {
+        let accounts: Vec<Account> = self.uid_map.iter().map(|a| a.clone()).collect();
+        accounts
+            .iter()
+            .map(|account| {
+                let account = lock(account);
+                BalanceAccount {
+                    uid: account.uid,
+                    balance: account.balance,
+                    journal: account.journal.clone(),
+                    status: account.status,
+                }
+            })
+            .collect()
+    }
+
+    pub fn upstream_balance(&self) -> i64 {
+        self.upstream_balance.load(Ordering::SeqCst)
+    }
+
+    pub fn restore(&self, accounts: Vec<BalanceAccount>, upstream_balance: i64) {
+        self.uid_map.clear();
+        self.upstream_balance
+            .store(upstream_balance, Ordering::SeqCst);
+        let (mut last_entry, mut last_transaction) = (0, 0);
+        for account in accounts {
+            for entry in &account.journal {
+                last_entry = last_entry.max(entry.id);
+                last_transaction = last_transaction.max(entry.transaction_id);
+            }
+            self.uid_map
+                .insert(account.uid, Arc::new(Mutex::new(account)));
+        }
+        self.next_entry_id.store(last_entry + 1, Ordering::Relaxed);
+        self.next_transaction_id
+            .store(last_transaction + 1, Ordering::Relaxed);
+    }
+
+    pub fn dump_keys(&self) -> Vec<IdempotencyRecord> {
+        self.keys.iter().map(|key| key.value().clone()).collect()
+    }
+
+    pub fn restore_keys(&self, keys: Vec<IdempotencyRecord>) {
+        self.keys.clear();
+        for key in keys {
+            self.remember_key(key);
+        }
+    }
+
+    pub fn remember_key(&self, key: IdempotencyRecord) {
+        self.keys.insert((key.scope, key.key.clone()), key);
+    }
+
+    pub fn dump_jobs(&self) -> Vec<BatchJob> {
+        self.jobs.iter().map(|job| job.value().clone()).collect()
+    }
+
+    pub fn restore_jobs(&self, jobs: Vec<BatchJob>) {
+        self.jobs.clear();
+        for job in jobs {
+            self.insert_job(job);
+        }
+    }
+
+    pub fn insert_job(&self, job: BatchJob) {
+        self.jobs.insert(job.batch_pay_id.clone(), job);
+    }
+
+    // check_job_update validates a job step without applying it
+    pub fn check_job_update(&self, batch_pay_id: &str, update: &JobUpdate) -> Result<()> {
+        let job = self
+            .jobs
+            .get(batch_pay_id)
+            .ok_or_else(|| EngineError::JobNotFound(batch_pay_id.to_string()))?;
+        job.check(update)?;
+        if let JobUpdate::Credit { uid, amount } = update {
+            self.check_add_money(*uid, *amount)?;
+        }
+        Ok(())
+    }
+
+    // update_job_at applies a job step, the job stays locked while a credit
+    // is posted so the two can not be observed apart. A confirmed get_pay is
+    // credited right away, if that fails it is only collected and the
+    // drain credits or fails it at the end
+    pub fn update_job_at(
+        &self,
+        batch_pay_id: &str,
+        update: &JobUpdate,
+        timestamp: u64,
+    ) -> Result<()> {
+        // taken before the job, close_at looks at the jobs while holding it
+        let _draining = matches!(update, JobUpdate::Draining { .. })
+            .then(|| self.closing.read().unwrap_or_else(PoisonError::into_inner));
+        let mut job = self
+            .jobs
+            .get_mut(batch_pay_id)
+            .ok_or_else(|| EngineError::JobNotFound(batch_pay_id.to_string()))?;
+        job.check(update)?;
+        let mut credit = None;
+        match update {
+            JobUpdate::Credit { uid, amount } => self.add_money_at(*uid, *amount, timestamp)?,
+            JobUpdate::Settled {
                 uid,
-                balance: amount,
-            };
-            self.uid_map.insert(uid, account);
+                transaction_id,
+                confirmed: true,
+            } => {
+                if let Some(amount) = job.in_flight(*uid, transaction_id) {
+                    if let Err(err) = self.add_money_at(*uid, amount, timestamp) {
+                        tracing::warn!("uid: {}, failed to credit {}: {}", uid, amount, err);
+                    } else {
+                        credit = Some(JobUpdate::Credit { uid: *uid, amount });
+                    }
+                }
+            }
+            _ => {}
         }
+        job.apply(update, timestamp);
+        if let Some(credit) = credit {
+            job.apply(&credit, timestamp);
+        }
+        Ok(())
     }
 
-    fn get_balance(&self, uid: i64) -> Result<i64> {
-        let account = self
+    // lock_all locks the accounts in uid order, so concurrent transactions can not deadlock
+    fn lock_all(accounts: &[(i64, Account)]) -> Vec<Guard<'_>> {
+        let mut order: Vec<usize> = (0..accounts.len()).collect();
+        order.sort_by_key(|i| accounts[*i].0);
+        let mut guards: Vec<Option<Guard<'_>>> = accounts.iter().map(|_| None).collect();
+        for i in order {
+            guards[i] = Some(lock(&accounts[i].1));
+        }
+        guards.into_iter().flatten().collect()
+    }
+
+    // check_postings validates one balanced transaction against the locked
+    // accounts it touches without applying it, `guards[i]` must be the
+    // account of `postings[i]`
+    fn check_postings(guards: &[Guard<'_>], postings: &[Posting]) -> Result<()> {
+        if !ledger::is_balanced(postings) {
+            return Err(EngineError::Unbalanced);
+        }
+        for (account, posting) in guards.iter().zip(postings) {
+            account.status.check_posting(posting.uid, posting.kind)?;
+            let balance = account
+                .balance
+                .checked_add(posting.amount)
+                .ok_or(EngineError::Overflow(posting.uid))?;
+            if balance < 0 {
+                return Err(EngineError::InsufficientBalance);
+            }
+        }
+        Ok(())
+    }
+
+    // post applies all postings of one balanced transaction atomically,
+    // `accounts[i]` must be the account of `postings[i]`
+    fn post(
+        &self,
+        accounts: &[(i64, Account)],
+        postings: &[Posting],
+        timestamp: u64,
+    ) -> Result<()> {
+        let mut guards = Self::lock_all(accounts);
+        self.post_locked(&mut guards, postings, timestamp)
+    }
+
+    fn post_locked(
+        &self,
+        guards: &mut [Guard<'_>],
+        postings: &[Posting],
+        timestamp: u64,
+    ) -> Result<()> {
+        Self::check_postings(guards, postings)?;
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed);
+        for (account, posting) in guards.iter_mut().zip(postings) {
+            let id = self.next_entry_id.fetch_add(1, Ordering::Relaxed);
+            account.record(id, transaction_id, timestamp, posting);
+        }
+        Ok(())
+    }
+
+    // add_money will add balance to uid account
+    // if account do not exist, then just add a new one,
+    // outside of tests money only comes in through batch pay jobs
+    #[cfg(test)]
+    pub fn add_money(&self, uid: i64, amount: i64) -> Result<()> {
+        self.add_money_at(uid, amount, journal::now_millis())
+    }
+
+    // add_money_at is add_money with the time of the change given by the caller,
+    // so replaying a log reproduces the original journal
+    pub fn add_money_at(&self, uid: i64, amount: i64, timestamp: u64) -> Result<()> {
+        Self::check_credit(uid, amount)?;
+        let posting = ledger::batch_pay(uid, amount);
+        let account = self.account_or_create(uid);
+        let mut account = lock(&account);
+        account.status.check_posting(uid, posting.kind)?;
+        account
+            .balance
+            .checked_add(amount)
+            .ok_or(EngineError::Overflow(uid))?;
+        // the account stays locked, so the debit and the credit are seen together
+        self.upstream_balance
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| {
+                balance.checked_sub(amount)
+            })
+            .map_err(|_| EngineError::Overflow(ledger::UPSTREAM_FUND_UID))?;
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed);
+        let id = self.next_entry_id.fetch_add(1, Ordering::Relaxed);
+        account.record(id, transaction_id, timestamp, &posting);
+        Ok(())
+    }
+
+    fn check_user_account(uid: i64) -> Result<()> {
+        if ledger::is_system_account(uid) {
+            return Err(EngineError::SystemAccount(uid));
+        }
+        Ok(())
+    }
+
+    fn check_credit(uid: i64, amount: i64) -> Result<()> {
+        Self::check_user_account(uid)?;
+        if amount <= 0 {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        Ok(())
+    }
+
+    // check_add_money validates a credit without applying it
+    pub fn check_add_money(&self, uid: i64, amount: i64) -> Result<()> {
+        Self::check_credit(uid, amount)?;
+        if let Ok(account) = self.account(uid) {
+            let account = lock(&account);
+            account
+                .status
+                .check_posting(uid, journal::EntryKind::BatchPayCredit)?;
+            account
+                .balance
+                .checked_add(amount)
+                .ok_or(EngineError::Overflow(uid))?;
+        }
+        self.upstream_balance()
+            .checked_sub(amount)
+            .ok_or(EngineError::Overflow(ledger::UPSTREAM_FUND_UID))?;
+        Ok(())
+    }
+
+    fn accounts_for_transfer(&self, from: i64, to: i64, amount: i64) -> Result<(Account, Account)> {
+        Self::check_user_account(from)?;
+        Self::check_user_account(to)?;
+        if from == to {
+            return Err(EngineError::SelfTransfer);
+        }
+        if amount <= 0 {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        Ok((self.account(from)?, self.account(to)?))
+    }
+
+    // check_transfer validates a transfer without applying it
+    pub fn check_transfer(&self, from: i64, to: i64, amount: i64) -> Result<()> {
+        let (from_account, to_account) = self.accounts_for_transfer(from, to, amount)?;
+        let accounts = [(from, from_account), (to, to_account)];
+        let guards = Self::lock_all(&accounts);
+        Self::check_postings(&guards, &ledger::trade(from, to, amount))
+    }
+
+    pub fn transfer_at(&self, from: i64, to: i64, amount: i64, timestamp: u64) -> Result<()> {
+        let (from_account, to_account) = self.accounts_for_transfer(from, to, amount)?;
+        self.post(
+            &[(from, from_account), (to, to_account)],
+            &ledger::trade(from, to, amount),
+            timestamp,
+        )
+    }
+
+    // check_open validates opening uid, opening an account that exists is a no-op
+    pub fn check_open(&self, uid: i64) -> Result<()> {
+        Self::check_user_account(uid)?;
+        match self.account(uid) {
+            Ok(account) => lock(&account).status.check_change(uid),
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub fn account_status(&self, uid: i64) -> Result<AccountStatus> {
+        let account = self.account(uid)?;
+        let status = lock(&account).status;
+        Ok(status)
+    }
+
+    // check_set_frozen validates freezing or unfreezing uid without applying it
+    pub fn check_set_frozen(&self, uid: i64) -> Result<()> {
+        Self::check_user_account(uid)?;
+        let account = self.account(uid)?;
+        let status = lock(&account).status;
+        status.check_change(uid)
+    }
+
+    pub fn set_frozen(&self, uid: i64, frozen: bool) -> Result<()> {
+        Self::check_user_account(uid)?;
+        let account = self.account(uid)?;
+        let mut account = lock(&account);
+        account.status.check_change(uid)?;
+        account.status = match frozen {
+            true => AccountStatus::Frozen,
+            false => AccountStatus::Active,
+        };
+        Ok(())
+    }
+
+    // accounts_for_close returns the account to close, followed by the sweep target
+    fn accounts_for_close(&self, uid: i64, sweep_to: Option<i64>) -> Result<Vec<(i64, Account)>> {
+        Self::check_user_account(uid)?;
+        let mut accounts = vec![(uid, self.account(uid)?)];
+        // money a running batch pay pulls for uid could not be credited anymore
+        if self.jobs.iter().any(|job| job.is_draining(uid)) {
+            return Err(EngineError::AccountDraining(uid));
+        }
+        if let Some(to) = sweep_to {
+            Self::check_user_account(to)?;
+            if to == uid {
+                return Err(EngineError::SelfTransfer);
+            }
+            accounts.push((to, self.account(to)?));
+        }
+        Ok(accounts)
+    }
+
+    // sweep_for_close validates closing the first of the locked accounts
+    // and returns the postings that move its balance to the second one
+    fn sweep_for_close(guards: &[Guard<'_>]) -> Result<Vec<Posting>> {
+        for account in guards {
+            account.status.check_change(account.uid)?;
+        }
+        let account = &guards[0];
+        match (account.balance, guards.get(1)) {
+            (0, _) => Ok(vec![]),
+            (_, None) => Err(EngineError::BalanceNotZero(account.uid)),
+            (balance, Some(target)) => {
+                let postings = ledger::sweep(account.uid, target.uid, balance);
+                Self::check_postings(guards, &postings)?;
+                Ok(postings.to_vec())
+            }
+        }
+    }
+
+    // check_close validates closing uid without applying it
+    pub fn check_close(&self, uid: i64, sweep_to: Option<i64>) -> Result<()> {
+        let accounts = self.accounts_for_close(uid, sweep_to)?;
+        let guards = Self::lock_all(&accounts);
+        Self::sweep_for_close(&guards)?;
+        Ok(())
+    }
+
+    // close_at sweeps the balance of uid and closes it as one change, so
+    // no trade can get in between
+    pub fn close_at(&self, uid: i64, sweep_to: Option<i64>, timestamp: u64) -> Result<()> {
+        let _closing = self.closing.write().unwrap_or_else(PoisonError::into_inner);
+        let accounts = self.accounts_for_close(uid, sweep_to)?;
+        let mut guards = Self::lock_all(&accounts);
+        let sweep = Self::sweep_for_close(&guards)?;
+        if !sweep.is_empty() {
+            self.post_locked(&mut guards, &sweep, timestamp)?;
+        }
+        guards[0].status = AccountStatus::Closed;
+        Ok(())
+    }
+
+    // verify_ledger checks that the balances and the upstream counter sum
+    // to zero, that every balance matches its journal and that the entries
+    // of every transaction sum to zero, a batch pay credit is balanced by
+    // the counter. Callers must block writers, an account opened while it
+    // runs is not locked
+    pub fn verify_ledger(&self) -> Result<()> {
+        let accounts: Vec<(i64, Account)> = self
             .uid_map
-            .get(&uid)
-            .ok_or(anyhow!("can not find the account"))?;
-        Ok(account.balance)
+            .iter()
+            .map(|a| (*a.key(), a.value().clone()))
+            .collect();
+        let guards = Self::lock_all(&accounts);
+
+        let mut total = self.upstream_balance() as i128;
+        let mut transactions: HashMap<u64, i128> = HashMap::new();
+        for account in &guards {
+            total += account.balance as i128;
+            let journal_sum: i128 = account.journal.iter().map(|e| e.amount as i128).sum();
+            if journal_sum != account.balance as i128 {
+                return Err(EngineError::Corrupted(format!(
+                    "balance of {} is {} but its journal sums to {}",
+                    account.uid, account.balance, journal_sum
+                )));
+            }
+            for entry in &account.journal {
+                if entry.kind == journal::EntryKind::BatchPayCredit {
+                    continue;
+                }
+                *transactions.entry(entry.transaction_id).or_default() += entry.amount as i128;
+            }
+        }
+        if total != 0 {
+            return Err(EngineError::Corrupted(format!("ledger is off by {total}")));
+        }
+        if let Some((id, sum)) = transactions.iter().find(|(_, sum)| **sum != 0) {
+            return Err(EngineError::Corrupted(format!(
+                "transaction {id} is off by {sum}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Engine for MMap {
+    fn open_account(&self, uid: i64) -> Result<()> {
+        self.check_open(uid)?;
+        self.account_or_create(uid);
+        Ok(())
+    }
+
+    fn account_status(&self, uid: i64) -> Result<AccountStatus> {
+        MMap::account_status(self, uid)
+    }
+
+    fn freeze_account(&self, uid: i64) -> Result<()> {
+        self.set_frozen(uid, true)
+    }
+
+    fn unfreeze_account(&self, uid: i64) -> Result<()> {
+        self.set_frozen(uid, false)
+    }
+
+    fn close_account(&self, uid: i64, sweep_to: Option<i64>) -> Result<()> {
+        self.close_at(uid, sweep_to, journal::now_millis())
+    }
+
+    fn get_balance(&self, uid: i64) -> Result<i64> {
+        if ledger::is_system_account(uid) {
+            return Ok(self.upstream_balance());
+        }
+        let account = self.account(uid)?;
+        let balance = lock(&account).balance;
+        Ok(balance)
     }
 
     // transfer will transfer amount from 'from' account to 'to' account
     fn transfer(&self, from: i64, to: i64, amount: i64) -> Result<()> {
-        let mut from_account = self
-            .uid_map
-            .get_mut(&from)
-            .ok_or(anyhow!("can not find the account"))?;
-        if from_account.balance < amount {
-            return Err(anyhow!("insufficient balance"));
-        }
-        from_account.balance -= amount;
+        self.transfer_at(from, to, amount, journal::now_millis())
+    }
 
-        let mut to_account = self
-            .uid_map
-            .get_mut(&to)
-            .ok_or(anyhow!("can not find the account"))?;
-        to_account.balance += amount;
+    fn history(&self, uid: i64, offset: usize, limit: usize) -> Result<HistoryPage> {
+        let account = self.account(uid)?;
+        let account = lock(&account);
+        Ok(journal::page(&account.journal, offset, limit))
+    }
+
+    fn verify_ledger(&self) -> Result<()> {
+        MMap::verify_ledger(self)
+    }
+
+    fn remember(&self, key: IdempotencyRecord) -> Result<()> {
+        self.remember_key(key);
+        Ok(())
+    }
+
+    fn idempotency_keys(&self, since: u64) -> Vec<IdempotencyRecord> {
+        self.keys
+            .iter()
+            .filter(|key| key.timestamp >= since)
+            .map(|key| key.value().clone())
+            .collect()
+    }
+
+    fn idempotency_key(&self, scope: KeyScope, key: &str) -> Option<IdempotencyRecord> {
+        self.keys
+            .get(&(scope, key.to_string()))
+            .map(|key| key.value().clone())
+    }
+
+    fn expire_keys(&self, before: u64) {
+        self.keys.retain(|_, key| key.timestamp >= before);
+    }
 
+    fn start_job(&self, job: BatchJob, key: IdempotencyRecord) -> Result<()> {
+        self.insert_job(job);
+        self.remember_key(key);
         Ok(())
     }
+
+    fn update_job(&self, batch_pay_id: &str, update: JobUpdate) -> Result<()> {
+        self.update_job_at(batch_pay_id, &update, journal::now_millis())
+    }
+
+    fn job(&self, batch_pay_id: &str) -> Option<BatchJob> {
+        self.jobs.get(batch_pay_id).map(|job| job.value().clone())
+    }
+
+    fn unfinished_jobs(&self) -> Vec<BatchJob> {
+        self.jobs
+            .iter()
+            .filter(|job| job.is_running())
+            .map(|job| job.value().clone())
+            .collect()
+    }
+
+    // an aborted job that still has transactions in flight is kept, an
+    // operator has to settle them first
+    fn expire_jobs(&self, before: u64) {
+        self.jobs.retain(|_, job| {
+            job.finished_at.is_none_or(|at| at >= before) || !job.unresolved().is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{super::journal::EntryKind, *};
+
+    #[test]
+    fn test_confirmed_get_pay_is_credited_on_settle() {
+        let engine = MMap::new();
+        let key = IdempotencyRecord::batch_pay("job-1", 0);
+        let job = BatchJob::new("job-1".to_string(), &[1], "finish".to_string(), 0);
+        engine.start_job(job, key).unwrap();
+        let step = |update| engine.update_job("job-1", update).unwrap();
+        let sending = |tid: &str, amount| JobUpdate::Sending {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            amount,
+        };
+        let settled = |tid: &str, confirmed| JobUpdate::Settled {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            confirmed,
+        };
+
+        step(sending("a", 100));
+        step(sending("b", 50));
+        step(sending("c", i64::MAX));
+        step(settled("a", true));
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        step(settled("a", true));
+        step(settled("b", false));
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        let history = engine.history(1, 0, 10).unwrap();
+        assert_eq!(history.total, 1);
+        assert_eq!(history.entries[0].kind, EntryKind::BatchPayCredit);
+
+        // confirmed money that can not be credited is still collected
+        step(settled("c", true));
+        let checkpoint = &engine.job("job-1").unwrap().uids[&1];
+        assert_eq!(checkpoint.collected, 100i64.saturating_add(i64::MAX));
+        assert_eq!(checkpoint.credited, 100);
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        engine.verify_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_aborted_job_keeps_unresolved_transactions() {
+        let engine = MMap::new();
+        let key = IdempotencyRecord::batch_pay("job-1", 0);
+        let job = BatchJob::new("job-1".to_string(), &[1], "finish".to_string(), 0);
+        engine.start_job(job, key).unwrap();
+        let step = |update| engine.update_job("job-1", update).unwrap();
+        step(JobUpdate::Sending {
+            uid: 1,
+            transaction_id: "a".to_string(),
+            amount: 100,
+        });
+        step(JobUpdate::Abort {
+            error: "1 transactions unresolved after 10 rounds: a".to_string(),
+        });
+        engine.expire_jobs(u64::MAX);
+        assert_eq!(engine.job("job-1").unwrap().unresolved().len(), 1);
+
+        // once it is settled the job expires like any other
+        step(JobUpdate::Settled {
+            uid: 1,
+            transaction_id: "a".to_string(),
+            confirmed: true,
+        });
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        engine.expire_jobs(u64::MAX);
+        assert!(engine.job("job-1").is_none());
+    }
+
+    #[test]
+    fn test_close_waits_for_drain() {
+        let engine = MMap::new();
+        let key = IdempotencyRecord::batch_pay("job-1", 0);
+        let job = BatchJob::new("job-1".to_string(), &[1, 2], "finish".to_string(), 0);
+        engine.start_job(job, key).unwrap();
+        engine.open_account(1).unwrap();
+        engine.open_account(2).unwrap();
+        let step = |update| engine.update_job("job-1", update).unwrap();
+
+        // a pending uid may still be closed, the drain sees it and fails it
+        engine.close_account(2, None).unwrap();
+        step(JobUpdate::Draining { uid: 1 });
+        assert!(matches!(
+            engine.close_account(1, None),
+            Err(EngineError::AccountDraining(1))
+        ));
+        step(JobUpdate::Sending {
+            uid: 1,
+            transaction_id: "a".to_string(),
+            amount: 100,
+        });
+        step(JobUpdate::Failed {
+            uid: 1,
+            error: "gave up".to_string(),
+        });
+        // the unanswered get_pay may still be credited
+        assert!(matches!(
+            engine.close_account(1, None),
+            Err(EngineError::AccountDraining(1))
+        ));
+        step(JobUpdate::Settled {
+            uid: 1,
+            transaction_id: "a".to_string(),
+            confirmed: false,
+        });
+        engine.close_account(1, None).unwrap();
+    }
+
+    #[test]
+    fn test_transfer_validates_before_mutating() {
+        let engine = MMap::new();
+        engine.add_money(1, 100).unwrap();
+        engine.open_account(3).unwrap();
+        assert!(matches!(
+            engine.transfer(1, 1, 10),
+            Err(EngineError::SelfTransfer)
+        ));
+        assert!(matches!(
+            engine.transfer(1, 2, 10),
+            Err(EngineError::AccountNotFound(2))
+        ));
+        assert!(matches!(
+            engine.transfer(2, 1, 10),
+            Err(EngineError::AccountNotFound(2))
+        ));
+        assert!(matches!(
+            engine.transfer(1, 3, 101),
+            Err(EngineError::InsufficientBalance)
+        ));
+        assert!(engine.transfer(ledger::UPSTREAM_FUND_UID, 1, 10).is_err());
+        assert!(engine.transfer(1, ledger::UPSTREAM_FUND_UID, 10).is_err());
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        assert_eq!(engine.get_balance(3).unwrap(), 0);
+        assert_eq!(engine.history(3, 0, 10).unwrap().total, 0);
+    }
+
+    #[test]
+    fn test_amount_checks() {
+        let engine = MMap::new();
+        engine.add_money(1, 100).unwrap();
+        engine.open_account(2).unwrap();
+        assert!(matches!(
+            engine.add_money(1, 0),
+            Err(EngineError::InvalidAmount(0))
+        ));
+        assert!(matches!(
+            engine.add_money(1, -5),
+            Err(EngineError::InvalidAmount(-5))
+        ));
+        // a negative transfer must not move money backwards
+        assert!(matches!(
+            engine.transfer(2, 1, -50),
+            Err(EngineError::InvalidAmount(-50))
+        ));
+        assert!(matches!(
+            engine.add_money(1, i64::MAX),
+            Err(EngineError::Overflow(1))
+        ));
+        assert!(matches!(
+            engine.check_add_money(1, i64::MAX),
+            Err(EngineError::Overflow(1))
+        ));
+        assert!(matches!(
+            engine.open_account(ledger::UPSTREAM_FUND_UID),
+            Err(EngineError::SystemAccount(_))
+        ));
+        assert_eq!(engine.get_balance(1).unwrap(), 100);
+        assert_eq!(engine.get_balance(2).unwrap(), 0);
+        engine.verify_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_account_lifecycle() {
+        let engine = MMap::new();
+        engine.add_money(1, 100).unwrap();
+        engine.add_money(2, 10).unwrap();
+        engine.open_account(3).unwrap();
+
+        // a frozen account still receives money, but can not send it
+        engine.freeze_account(1).unwrap();
+        assert_eq!(engine.account_status(1).unwrap(), AccountStatus::Frozen);
+        assert!(matches!(
+            engine.transfer(1, 2, 10),
+            Err(EngineError::AccountFrozen(1))
+        ));
+        assert!(matches!(
+            engine.check_transfer(1, 2, 10),
+            Err(EngineError::AccountFrozen(1))
+        ));
+        engine.transfer(2, 1, 10).unwrap();
+        engine.add_money(1, 5).unwrap();
+        engine.unfreeze_account(1).unwrap();
+        engine.transfer(1, 2, 15).unwrap();
+
+        // money left in an account has to go somewhere
+        assert!(matches!(
+            engine.close_account(1, None),
+            Err(EngineError::BalanceNotZero(1))
+        ));
+        assert!(matches!(
+            engine.close_account(1, Some(1)),
+            Err(EngineError::SelfTransfer)
+        ));
+        assert!(matches!(
+            engine.close_account(1, Some(ledger::UPSTREAM_FUND_UID)),
+            Err(EngineError::SystemAccount(_))
+        ));
+        engine.freeze_account(1).unwrap();
+        engine.check_close(1, Some(3)).unwrap();
+        engine.close_account(1, Some(3)).unwrap();
+        assert_eq!(engine.account_status(1).unwrap(), AccountStatus::Closed);
+        assert_eq!(engine.get_balance(1).unwrap(), 0);
+        assert_eq!(engine.get_balance(3).unwrap(), 100);
+        let swept = &engine.history(3, 0, 10).unwrap().entries[0];
+        assert_eq!(swept.kind, EntryKind::SweepCredit);
+        assert_eq!(swept.counterparty, Some(1));
+
+        // a closed account takes no change at all
+        assert!(matches!(
+            engine.transfer(2, 1, 1),
+            Err(EngineError::AccountClosed(1))
+        ));
+        assert!(matches!(
+            engine.add_money(1, 1),
+            Err(EngineError::AccountClosed(1))
+        ));
+        assert!(matches!(
+            engine.check_add_money(1, 1),
+            Err(EngineError::AccountClosed(1))
+        ));
+        assert!(matches!(
+            engine.close_account(3, Some(1)),
+            Err(EngineError::AccountClosed(1))
+        ));
+        for result in [
+            engine.open_account(1),
+            engine.freeze_account(1),
+            engine.unfreeze_account(1),
+            engine.close_account(1, None),
+        ] {
+            assert!(matches!(result, Err(EngineError::AccountClosed(1))));
+        }
+        assert!(matches!(
+            engine.freeze_account(4),
+            Err(EngineError::AccountNotFound(4))
+        ));
+        engine.open_account(2).unwrap();
+        engine.verify_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_batch_pay_debits_upstream() {
+        let engine = MMap::new();
+        engine.add_money(1, 100).unwrap();
+        engine.add_money(2, 50).unwrap();
+        assert_eq!(engine.get_balance(ledger::UPSTREAM_FUND_UID).unwrap(), -150);
+        assert!(matches!(
+            engine.history(ledger::UPSTREAM_FUND_UID, 0, 10),
+            Err(EngineError::AccountNotFound(_))
+        ));
+        let user = engine.history(2, 0, 10).unwrap();
+        assert_eq!(user.entries[0].kind, EntryKind::BatchPayCredit);
+        assert_eq!(
+            user.entries[0].counterparty,
+            Some(ledger::UPSTREAM_FUND_UID)
+        );
+        engine.verify_ledger().unwrap();
+
+        // a failed credit leaves the counter alone
+        assert!(engine.add_money(1, i64::MAX).is_err());
+        assert_eq!(engine.get_balance(ledger::UPSTREAM_FUND_UID).unwrap(), -150);
+        engine.verify_ledger().unwrap();
+    }
+
+    #[test]
+    fn test_journal_history() {
+        let engine = MMap::new();
+        engine.add_money(1, 100).unwrap();
+        engine.open_account(2).unwrap();
+        engine.transfer(1, 2, 30).unwrap();
+        assert!(engine.transfer(1, 2, 1000).is_err());
+
+        let page = engine.history(1, 0, 10).unwrap();
+        assert_eq!(page.total, 2);
+        let kinds: Vec<_> = page.entries.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EntryKind::TradeDebit, EntryKind::BatchPayCredit]);
+        assert_eq!(page.entries[0].amount, -30);
+        assert_eq!(page.entries[0].balance, 70);
+        assert_eq!(page.entries[0].counterparty, Some(2));
+
+        let page = engine.history(2, 0, 10).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.entries[0].kind, EntryKind::TradeCredit);
+        assert!(engine.history(2, 1, 10).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_transfer_conserves_total() {
+        const ACCOUNTS: i64 = 8;
+        const THREADS: u64 = 8;
+        const ROUNDS: u64 = 20000;
+
+        let engine = Arc::new(MMap::new());
+        for uid in 0..ACCOUNTS {
+            engine.add_money(uid, 1000).unwrap();
+        }
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|seed| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    // xorshift, good enough to pick random pairs
+                    let mut state = (seed + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                    let mut next = move || {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        state
+                    };
+                    for _ in 0..ROUNDS {
+                        let from = (next() % ACCOUNTS as u64) as i64;
+                        let to = (next() % ACCOUNTS as u64) as i64;
+                        let amount = (next() % 300) as i64;
+                        let _ = engine.transfer(from, to, amount);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut total = 0;
+        for uid in 0..ACCOUNTS {
+            let balance = engine.get_balance(uid).unwrap();
+            assert!(balance >= 0);
+            total += balance;
+        }
+        assert_eq!(total, ACCOUNTS * 1000);
+        engine.verify_ledger().unwrap();
+    }
 }
@@ -1,2 +1,10 @@
+pub mod account;
 pub mod api;
-pub mod mmap;
\ No newline at end of file
+pub mod batch;
+pub mod error;
+pub mod idempotency;
+pub mod journal;
+pub mod ledger;
+pub mod mmap;
+pub mod snapshot;
+pub mod wal;
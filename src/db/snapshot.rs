@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{batch::BatchJob, idempotency::IdempotencyRecord, mmap::BalanceAccount};
+
+const PREFIX: &str = "snapshot-";
+
+// point-in-time copy of all accounts, covering every log record up to `lsn`
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub lsn: u64,
+    pub accounts: Vec<BalanceAccount>,
+    // balance of the upstream system account, it has no journal
+    pub upstream_balance: i64,
+    #[serde(default)]
+    pub keys: Vec<IdempotencyRecord>,
+    #[serde(default)]
+    pub jobs: Vec<BatchJob>,
+}
+
+// file layout: "<crc32 in hex>\n<json body>"
+pub fn write(dir: &Path, snapshot: &Snapshot) -> Result<PathBuf> {
+    let body = serde_json::to_vec(snapshot)?;
+    let checksum = crc32fast::hash(&body);
+    let path = dir.join(format!("{PREFIX}{:020}", snapshot.lsn));
+    let tmp = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(format!("{checksum:08x}\n").as_bytes())?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    sync_dir(dir)?;
+    Ok(path)
+}
+
+pub fn read(path: &Path) -> Result<Snapshot> {
+    let mut buf = vec![];
+    File::open(path)?.read_to_end(&mut buf)?;
+    let split = buf
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or(anyhow!("missing checksum header"))?;
+    let expected = u32::from_str_radix(std::str::from_utf8(&buf[..split])?, 16)?;
+    let body = &buf[split + 1..];
+    if crc32fast::hash(body) != expected {
+        return Err(anyhow!("checksum mismatch"));
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+// list returns all snapshot files in dir, newest first
+pub fn list(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("read dir {}", dir.display()))? {
+        let path = entry?.path();
+        let lsn = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|lsn| lsn.parse::<u64>().ok());
+        if let Some(lsn) = lsn {
+            snapshots.push((lsn, path));
+        }
+    }
+    snapshots.sort_by_key(|(lsn, _)| std::cmp::Reverse(*lsn));
+    Ok(snapshots)
+}
+
+// load_latest returns the newest snapshot that passes its checksum
+pub fn load_latest(dir: &Path) -> Result<Option<Snapshot>> {
+    for (_, path) in list(dir)? {
+        match read(&path) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(err) => tracing::warn!("skip broken snapshot {}: {}", path.display(), err),
+        }
+    }
+    Ok(None)
+}
+
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
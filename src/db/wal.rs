@@ -0,0 +1,750 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    account::AccountStatus,
+    api::Engine,
+    batch::{BatchJob, JobUpdate},
+    error::{self, EngineError},
+    idempotency::{IdempotencyRecord, KeyScope},
+    journal::{self, HistoryPage},
+    mmap::MMap,
+    snapshot::{self, Snapshot},
+};
+
+const LOG_FILE: &str = "balance.wal";
+
+// one line of the log, every mutation is appended before it is acknowledged
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    lsn: u64,
+    timestamp: u64,
+    #[serde(flatten)]
+    record: Record,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Open {
+        uid: i64,
+    },
+    Add {
+        uid: i64,
+        amount: i64,
+    },
+    Transfer {
+        from: i64,
+        to: i64,
+        amount: i64,
+        // the idempotency key of the trade, logged with it in the same record
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<IdempotencyRecord>,
+    },
+    Remember {
+        key: IdempotencyRecord,
+    },
+    StartJob {
+        job: BatchJob,
+        key: IdempotencyRecord,
+    },
+    Job {
+        batch_pay_id: String,
+        update: JobUpdate,
+    },
+    Freeze {
+        uid: i64,
+    },
+    Unfreeze {
+        uid: i64,
+    },
+    Close {
+        uid: i64,
+        // where the balance left in the account went
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sweep_to: Option<i64>,
+    },
+}
+
+struct Log {
+    file: File,
+    // lsn of the last appended record
+    lsn: u64,
+    // lsn covered by the newest snapshot on disk
+    snapshot_lsn: u64,
+}
+
+// Wal keeps balances in memory like MMap, but appends every change
+// to an fsync'd log file, and recovers from the latest snapshot plus the log tail
+pub struct Wal {
+    inner: MMap,
+    dir: PathBuf,
+    // all mutations are serialized through this lock, so the order
+    // of the log always matches the order they were applied in
+    log: Mutex<Log>,
+    // held for a whole snapshot, so two of them never interleave
+    snapshotting: Mutex<()>,
+}
+
+impl Wal {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("create data dir {}", dir.display()))?;
+
+        let inner = MMap::new();
+        let snapshot_lsn = match snapshot::load_latest(&dir)? {
+            Some(snapshot) => {
+                tracing::info!("loaded snapshot at lsn {}", snapshot.lsn);
+                inner.restore(snapshot.accounts, snapshot.upstream_balance);
+                inner.restore_keys(snapshot.keys);
+                inner.restore_jobs(snapshot.jobs);
+                snapshot.lsn
+            }
+            None => 0,
+        };
+
+        let path = dir.join(LOG_FILE);
+        let mut file = open_log(&path)?;
+        let (valid_len, lsn) = replay(&file, &inner, snapshot_lsn)?;
+        if valid_len < file.metadata()?.len() {
+            // the last record was torn by a crash, it was never acknowledged
+            tracing::warn!("truncating torn tail of {}", path.display());
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Wal {
+            inner,
+            dir,
+            log: Mutex::new(Log {
+                file,
+                lsn,
+                snapshot_lsn,
+            }),
+            snapshotting: Mutex::new(()),
+        })
+    }
+
+    // append validates a change, makes it durable and only then applies it,
+    // nothing else can mutate the engine in between
+    fn append(
+        &self,
+        record: Record,
+        check: impl Fn(&MMap) -> error::Result<()>,
+        apply: impl Fn(&MMap, u64) -> error::Result<()>,
+    ) -> error::Result<()> {
+        let mut log = self.log.lock().map_err(|_| anyhow!("log lock poisoned"))?;
+        check(&self.inner)?;
+        let entry = Entry {
+            lsn: log.lsn + 1,
+            timestamp: journal::now_millis(),
+            record,
+        };
+        write_entry(&mut log.file, &entry).context("failed to append to log")?;
+        log.lsn = entry.lsn;
+        apply(&self.inner, entry.timestamp)
+    }
+
+    #[cfg(test)]
+    fn add_money(&self, uid: i64, amount: i64) -> error::Result<()> {
+        self.append(
+            Record::Add { uid, amount },
+            |inner| inner.check_add_money(uid, amount),
+            |inner, timestamp| inner.add_money_at(uid, amount, timestamp),
+        )
+    }
+
+    // snapshot writes all accounts to a new snapshot file, then drops every
+    // log record and snapshot that is older than the previous snapshot,
+    // so a torn newest snapshot can still fall back to the previous one.
+    // Writers are only stopped while the state is copied and while the
+    // records they appended in the meantime are carried over to the new log
+    fn take_snapshot(&self) -> Result<()> {
+        let _snapshotting = self
+            .snapshotting
+            .lock()
+            .map_err(|_| anyhow!("snapshot lock poisoned"))?;
+        let (snapshot, log_len) = {
+            let log = self.log.lock().map_err(|_| anyhow!("log lock poisoned"))?;
+            if log.lsn == log.snapshot_lsn {
+                return Ok(());
+            }
+            let snapshot = Snapshot {
+                lsn: log.lsn,
+                accounts: self.inner.dump(),
+                upstream_balance: self.inner.upstream_balance(),
+                keys: self.inner.dump_keys(),
+                jobs: self.inner.dump_jobs(),
+            };
+            (snapshot, log.file.metadata()?.len())
+        };
+        let lsn = snapshot.lsn;
+        let path = snapshot::write(&self.dir, &snapshot)?;
+        drop(snapshot);
+        // make sure it can be read back before throwing anything away
+        snapshot::read(&path).context("snapshot verification failed")?;
+
+        let snapshots = snapshot::list(&self.dir)?;
+        let keep_after = snapshots.get(1).map(|(lsn, _)| *lsn).unwrap_or(0);
+        self.compact(keep_after, log_len, lsn)?;
+        for (_, old) in snapshots.iter().skip(2) {
+            fs::remove_file(old)?;
+        }
+        tracing::info!(
+            "snapshot at lsn {}, log compacted after lsn {}",
+            lsn,
+            keep_after
+        );
+        Ok(())
+    }
+
+    // compact rewrites the log so it only holds records newer than
+    // `keep_after`. The first `log_len` bytes are copied while writers go
+    // on, then the log is locked for the records appended after them
+    fn compact(&self, keep_after: u64, log_len: u64, snapshot_lsn: u64) -> Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut reader = BufReader::new(File::open(&path)?.take(log_len));
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let entry: Entry = serde_json::from_str(line.trim_end())?;
+            if entry.lsn > keep_after {
+                writer.write_all(line.as_bytes())?;
+            }
+            line.clear();
+        }
+
+        let mut log = self.log.lock().map_err(|_| anyhow!("log lock poisoned"))?;
+        // everything appended since the state was copied is newer than the snapshot
+        io::copy(&mut reader.into_inner().into_inner(), &mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        snapshot::sync_dir(&self.dir)?;
+
+        log.file = open_log(&path)?;
+        log.file.seek(SeekFrom::End(0))?;
+        log.snapshot_lsn = snapshot_lsn;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open log {}", path.display()))
+}
+
+// replay applies every record newer than `after` and
+// returns the length of the valid prefix and the last lsn. The records
+// must continue right after `after` without a gap, otherwise the engine
+// would start with changes missing, so such a log is refused
+fn replay(file: &File, engine: &MMap, after: u64) -> Result<(u64, u64)> {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let (mut offset, mut lsn, mut count) = (0u64, after, 0usize);
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        if !line.ends_with('\n') {
+            // incomplete last line
+            break;
+        }
+        let entry: Entry = serde_json::from_str(line.trim_end())
+            .with_context(|| format!("corrupted log record at offset {offset}"))?;
+        offset += n as u64;
+        if entry.lsn <= after {
+            // already part of the snapshot
+            continue;
+        }
+        if entry.lsn != lsn + 1 {
+            return Err(anyhow!(
+                "log skips from lsn {} to {}, records are missing",
+                lsn,
+                entry.lsn
+            ));
+        }
+        match entry.record {
+            Record::Open { uid } => engine.open_account(uid)?,
+            Record::Add { uid, amount } => engine.add_money_at(uid, amount, entry.timestamp)?,
+            Record::Transfer {
+                from,
+                to,
+                amount,
+                key,
+            } => {
+                engine.transfer_at(from, to, amount, entry.timestamp)?;
+                if let Some(key) = key {
+                    engine.remember_key(key);
+                }
+            }
+            Record::Remember { key } => engine.remember_key(key),
+            Record::StartJob { job, key } => {
+                engine.insert_job(job);
+                engine.remember_key(key);
+            }
+            Record::Job {
+                batch_pay_id,
+                update,
+            } => engine.update_job_at(&batch_pay_id, &update, entry.timestamp)?,
+            Record::Freeze { uid } => engine.set_frozen(uid, true)?,
+            Record::Unfreeze { uid } => engine.set_frozen(uid, false)?,
+            Record::Close { uid, sweep_to } => engine.close_at(uid, sweep_to, entry.timestamp)?,
+        }
+        lsn = entry.lsn;
+        count += 1;
+    }
+    tracing::info!("replayed {} log records", count);
+    Ok((offset, lsn))
+}
+
+fn write_entry(file: &mut File, entry: &Entry) -> Result<()> {
+    let mut buf = serde_json::to_vec(entry)?;
+    buf.push(b'\n');
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+impl Engine for Wal {
+    fn open_account(&self, uid: i64) -> error::Result<()> {
+        if self.inner.get_balance(uid).is_ok() {
+            return self.inner.check_open(uid);
+        }
+        self.append(
+            Record::Open { uid },
+            |inner| inner.check_open(uid),
+            |inner, _| inner.open_account(uid),
+        )
+    }
+
+    fn account_status(&self, uid: i64) -> error::Result<AccountStatus> {
+        self.inner.account_status(uid)
+    }
+
+    fn freeze_account(&self, uid: i64) -> error::Result<()> {
+        self.append(
+            Record::Freeze { uid },
+            |inner| inner.check_set_frozen(uid),
+            |inner, _| inner.set_frozen(uid, true),
+        )
+    }
+
+    fn unfreeze_account(&self, uid: i64) -> error::Result<()> {
+        self.append(
+            Record::Unfreeze { uid },
+            |inner| inner.check_set_frozen(uid),
+            |inner, _| inner.set_frozen(uid, false),
+        )
+    }
+
+    fn close_account(&self, uid: i64, sweep_to: Option<i64>) -> error::Result<()> {
+        self.append(
+            Record::Close { uid, sweep_to },
+            |inner| inner.check_close(uid, sweep_to),
+            |inner, timestamp| inner.close_at(uid, sweep_to, timestamp),
+        )
+    }
+
+    fn get_balance(&self, uid: i64) -> error::Result<i64> {
+        self.inner.get_balance(uid)
+    }
+
+    fn transfer(&self, from: i64, to: i64, amount: i64) -> error::Result<()> {
+        self.append(
+            Record::Transfer {
+                from,
+                to,
+                amount,
+                key: None,
+            },
+            |inner| inner.check_transfer(from, to, amount),
+            |inner, timestamp| inner.transfer_at(from, to, amount, timestamp),
+        )
+    }
+
+    fn transfer_once(
+        &self,
+        from: i64,
+        to: i64,
+        amount: i64,
+        key: IdempotencyRecord,
+    ) -> error::Result<()> {
+        self.append(
+            Record::Transfer {
+                from,
+                to,
+                amount,
+                key: Some(key.clone()),
+            },
+            |inner| inner.check_transfer(from, to, amount),
+            |inner, timestamp| {
+                inner.transfer_at(from, to, amount, timestamp)?;
+                inner.remember_key(key.clone());
+                Ok(())
+            },
+        )
+    }
+
+    fn remember(&self, key: IdempotencyRecord) -> error::Result<()> {
+        self.append(
+            Record::Remember { key: key.clone() },
+            |_| Ok(()),
+            |inner, _| {
+                inner.remember_key(key.clone());
+                Ok(())
+            },
+        )
+    }
+
+    fn idempotency_keys(&self, since: u64) -> Vec<IdempotencyRecord> {
+        self.inner.idempotency_keys(since)
+    }
+
+    fn idempotency_key(&self, scope: KeyScope, key: &str) -> Option<IdempotencyRecord> {
+        self.inner.idempotency_key(scope, key)
+    }
+
+    // expired keys are only dropped in memory, the next snapshot leaves them out
+    fn expire_keys(&self, before: u64) {
+        self.inner.expire_keys(before)
+    }
+
+    fn start_job(&self, job: BatchJob, key: IdempotencyRecord) -> error::Result<()> {
+        self.append(
+            Record::StartJob {
+                job: job.clone(),
+                key: key.clone(),
+            },
+            |_| Ok(()),
+            |inner, _| {
+                inner.insert_job(job.clone());
+                inner.remember_key(key.clone());
+                Ok(())
+            },
+        )
+    }
+
+    fn update_job(&self, batch_pay_id: &str, update: JobUpdate) -> error::Result<()> {
+        self.append(
+            Record::Job {
+                batch_pay_id: batch_pay_id.to_string(),
+                update: update.clone(),
+            },
+            |inner| inner.check_job_update(batch_pay_id, &update),
+            |inner, timestamp| inner.update_job_at(batch_pay_id, &update, timestamp),
+        )
+    }
+
+    fn job(&self, batch_pay_id: &str) -> Option<BatchJob> {
+        self.inner.job(batch_pay_id)
+    }
+
+    fn unfinished_jobs(&self) -> Vec<BatchJob> {
+        self.inner.unfinished_jobs()
+    }
+
+    // like keys, finished jobs are dropped in memory and left out of the next snapshot
+    fn expire_jobs(&self, before: u64) {
+        self.inner.expire_jobs(before)
+    }
+
+    fn history(&self, uid: i64, offset: usize, limit: usize) -> error::Result<HistoryPage> {
+        self.inner.history(uid, offset, limit)
+    }
+
+    fn verify_ledger(&self) -> error::Result<()> {
+        self.inner.verify_ledger()
+    }
+
+    fn snapshot(&self) -> error::Result<()> {
+        self.take_snapshot().map_err(EngineError::Storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, io::Write};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        env::temp_dir().join(format!("balance-wal-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_replay_after_restart() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.add_money(2, 50).unwrap();
+            wal.transfer(1, 2, 300).unwrap();
+            assert!(wal.transfer(2, 1, 10000).is_err());
+        }
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 700);
+        assert_eq!(wal.get_balance(2).unwrap(), 350);
+        let before = wal.history(2, 0, 10).unwrap().entries;
+        drop(wal);
+        let wal = Wal::open(&dir).unwrap();
+        let after = wal.history(2, 0, 10).unwrap().entries;
+        assert_eq!(after.len(), 2);
+        assert_eq!(before[0].id, after[0].id);
+        assert_eq!(before[0].transaction_id, after[0].transaction_id);
+        assert_eq!(before[0].timestamp, after[0].timestamp);
+        wal.verify_ledger().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_idempotency_keys_survive_restart() {
+        let dir = temp_dir();
+        let key = |key: &str| IdempotencyRecord {
+            scope: KeyScope::Trade,
+            key: key.to_string(),
+            fingerprint: 1,
+            timestamp: 100,
+            outcome: None,
+        };
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.add_money(2, 1).unwrap();
+            wal.transfer_once(1, 2, 10, key("a")).unwrap();
+            // a failed transfer must not remember its key
+            assert!(wal.transfer_once(1, 2, 5000, key("b")).is_err());
+            wal.snapshot().unwrap();
+            wal.remember(key("c")).unwrap();
+        }
+        let wal = Wal::open(&dir).unwrap();
+        let mut keys: Vec<_> = wal.idempotency_keys(0).into_iter().map(|k| k.key).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "c"]);
+        assert_eq!(wal.get_balance(2).unwrap(), 11);
+
+        wal.expire_keys(101);
+        assert!(wal.idempotency_keys(0).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_batch_job_survives_restart() {
+        let dir = temp_dir();
+        let key = IdempotencyRecord::batch_pay("job-1", 100);
+        let sending = |tid: &str, amount| JobUpdate::Sending {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            amount,
+        };
+        let settled = |tid: &str| JobUpdate::Settled {
+            uid: 1,
+            transaction_id: tid.to_string(),
+            confirmed: true,
+        };
+        {
+            let wal = Wal::open(&dir).unwrap();
+            let job = BatchJob::new("job-1".to_string(), &[1, 2], "finish".to_string(), 100);
+            wal.start_job(job, key).unwrap();
+            wal.update_job("job-1", sending("a", 300)).unwrap();
+            // a confirmed get_pay is credited when it settles
+            wal.update_job("job-1", settled("a")).unwrap();
+            // more than collected can never be credited
+            assert!(wal
+                .update_job("job-1", JobUpdate::Credit { uid: 1, amount: 1 })
+                .is_err());
+            wal.snapshot().unwrap();
+            wal.update_job("job-1", sending("b", 50)).unwrap();
+        }
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 300);
+        assert_eq!(wal.idempotency_keys(0).len(), 1);
+        let jobs = wal.unfinished_jobs();
+        assert_eq!(jobs.len(), 1);
+        let checkpoint = &jobs[0].uids[&1];
+        assert_eq!((checkpoint.collected, checkpoint.credited), (300, 300));
+        assert_eq!(checkpoint.in_flight.get("b"), Some(&50));
+        assert_eq!(jobs[0].finish_request_id, "finish");
+
+        wal.update_job("job-1", settled("b")).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 350);
+        wal.update_job("job-1", JobUpdate::Finished).unwrap();
+        assert!(wal.unfinished_jobs().is_empty());
+        assert_eq!(wal.job("job-1").unwrap().uids[&1].collected, 350);
+        wal.expire_jobs(u64::MAX);
+        assert!(wal.job("job-1").is_none());
+        wal.verify_ledger().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_account_status_survives_restart() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.add_money(2, 10).unwrap();
+            wal.open_account(3).unwrap();
+            wal.freeze_account(2).unwrap();
+            wal.snapshot().unwrap();
+            wal.close_account(1, Some(3)).unwrap();
+            // rejected changes are not logged
+            assert!(wal.close_account(2, None).is_err());
+            assert!(wal.freeze_account(1).is_err());
+            assert!(wal.open_account(1).is_err());
+        }
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.account_status(1).unwrap(), AccountStatus::Closed);
+        assert_eq!(wal.account_status(2).unwrap(), AccountStatus::Frozen);
+        assert_eq!(wal.get_balance(3).unwrap(), 1000);
+        assert!(wal.transfer(2, 3, 1).is_err());
+        wal.unfreeze_account(2).unwrap();
+        wal.close_account(2, Some(3)).unwrap();
+        wal.snapshot().unwrap();
+        drop(wal);
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.account_status(2).unwrap(), AccountStatus::Closed);
+        assert_eq!(wal.get_balance(3).unwrap(), 1010);
+        wal.verify_ledger().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail_is_dropped() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        file.write_all(br#"{"lsn":2,"op":"add","uid":1,"amo"#)
+            .unwrap();
+        drop(file);
+
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 1000);
+        wal.add_money(1, 1).unwrap();
+        drop(wal);
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 1001);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_snapshot_and_tail() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(2, 100).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(1, 5).unwrap();
+        }
+        assert_eq!(snapshot::list(&dir).unwrap().len(), 2);
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 1005);
+        assert_eq!(wal.get_balance(2).unwrap(), 100);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_while_writing() {
+        let dir = temp_dir();
+        {
+            let wal = std::sync::Arc::new(Wal::open(&dir).unwrap());
+            let writer = {
+                let wal = wal.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        wal.add_money(1, 1).unwrap();
+                    }
+                })
+            };
+            while !writer.is_finished() {
+                wal.snapshot().unwrap();
+            }
+            writer.join().unwrap();
+            wal.add_money(1, 1).unwrap();
+        }
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 501);
+        wal.verify_ledger().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_gap_in_log_is_refused() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(1, 20).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(1, 3).unwrap();
+        }
+        // the log was compacted, it does not go back to the first record
+        for (_, path) in snapshot::list(&dir).unwrap() {
+            fs::remove_file(path).unwrap();
+        }
+        let err = Wal::open(&dir).err().unwrap();
+        assert!(err.to_string().contains("records are missing"), "{err:#}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_zero_credit_is_not_replayed() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let record = r#"{"lsn":1,"timestamp":1,"op":"add","uid":1,"amount":0}"#;
+        fs::write(dir.join(LOG_FILE), format!("{record}\n")).unwrap();
+        assert!(Wal::open(&dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_snapshot_falls_back_to_previous() {
+        let dir = temp_dir();
+        {
+            let wal = Wal::open(&dir).unwrap();
+            wal.add_money(1, 1000).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(1, 20).unwrap();
+            wal.snapshot().unwrap();
+            wal.add_money(1, 3).unwrap();
+        }
+        let (_, newest) = &snapshot::list(&dir).unwrap()[0];
+        let len = fs::metadata(newest).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(newest)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.get_balance(1).unwrap(), 1023);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
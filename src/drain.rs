@@ -0,0 +1,292 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::{task::JoinSet, time};
+
+use crate::GLOBAL_CONFIG;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Chunk is the final answer of the upstream to one get_pay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunk {
+    Paid,
+    // 501, the account holds less than the amount asked for
+    Insufficient,
+    // 404, the upstream does not know the uid
+    UnknownUid,
+}
+
+// Payer pulls chunks of one uid from the upstream
+pub trait Payer: Send + Sync {
+    fn pay(&self, amount: i64) -> BoxFuture<'_, Result<Chunk>>;
+}
+
+// DrainStrategy decides which chunks to ask for until an account is empty,
+// it returns the cents pulled
+pub trait DrainStrategy: Send + Sync {
+    fn drain(&self, payer: Arc<dyn Payer>) -> BoxFuture<'_, Result<i64>>;
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StrategyKind {
+    Halving,
+    Exponential,
+}
+
+// pull_all pays `amount` from `workers` concurrent workers until the upstream
+// runs dry, workers start `stagger` apart and no more are started once one
+// of them ran dry. Returns the cents pulled and whether the uid is unknown
+async fn pull_all(
+    payer: &Arc<dyn Payer>,
+    amount: i64,
+    workers: usize,
+    stagger: Duration,
+) -> Result<(i64, bool)> {
+    let dry = Arc::new(AtomicBool::new(false));
+    let mut set = JoinSet::new();
+    for i in 0..workers.max(1) {
+        if i > 0 && !stagger.is_zero() {
+            time::sleep(stagger).await;
+        }
+        if dry.load(Ordering::Relaxed) {
+            break;
+        }
+        let (payer, dry) = (payer.clone(), dry.clone());
+        set.spawn(async move {
+            let mut pulled = 0i64;
+            let result = loop {
+                match payer.pay(amount).await {
+                    Ok(Chunk::Paid) => pulled += amount,
+                    Ok(Chunk::Insufficient) => break Ok(false),
+                    Ok(Chunk::UnknownUid) => break Ok(true),
+                    Err(err) => break Err(err),
+                }
+            };
+            dry.store(true, Ordering::Relaxed);
+            (pulled, result)
+        });
+    }
+
+    // wait for every worker, so no get_pay is still running when this returns
+    let (mut total, mut unknown, mut failed) = (0i64, false, None);
+    while let Some(joined) = set.join_next().await {
+        let (pulled, result) = joined?;
+        total += pulled;
+        match result {
+            Ok(stop) => unknown |= stop,
+            Err(err) => failed = Some(err),
+        }
+    }
+    match failed {
+        Some(err) => Err(err),
+        None => Ok((total, unknown)),
+    }
+}
+
+// Halving probes with `max_chunk` from `parallel` workers, then halves the
+// chunk down to one cent. Every level costs at least one request per worker,
+// so even an empty account takes ~40 round-trips
+pub struct Halving {
+    pub max_chunk: i64,
+    pub parallel: usize,
+}
+
+impl DrainStrategy for Halving {
+    fn drain(&self, payer: Arc<dyn Payer>) -> BoxFuture<'_, Result<i64>> {
+        Box::pin(async move {
+            let stagger = Duration::from_millis(10);
+            let (mut total, unknown) =
+                pull_all(&payer, self.max_chunk, self.parallel, stagger).await?;
+            if unknown {
+                return Ok(total);
+            }
+            let mut amount = self.max_chunk / 2;
+            while amount >= 1 {
+                let (pulled, unknown) =
+                    pull_all(&payer, amount, self.parallel.min(2), Duration::ZERO).await?;
+                total += pulled;
+                if unknown {
+                    break;
+                }
+                amount /= 2;
+            }
+            Ok(total)
+        })
+    }
+}
+
+// Exponential doubles the chunk from one cent until the upstream runs dry
+// or the chunk reaches `max_chunk`, where `parallel` workers take over.
+// What is left is then known to be below the last chunk, and a binary
+// search over powers of two below that bound finds it with about one
+// request per bit. An account of B cents costs about 2*log2(B) round-trips
+pub struct Exponential {
+    pub max_chunk: i64,
+    pub parallel: usize,
+}
+
+impl DrainStrategy for Exponential {
+    fn drain(&self, payer: Arc<dyn Payer>) -> BoxFuture<'_, Result<i64>> {
+        Box::pin(async move {
+            let (mut total, mut amount) = (0i64, 1i64);
+            // bound is what the account is known to hold less than
+            let mut bound = loop {
+                if amount >= self.max_chunk {
+                    let (pulled, unknown) =
+                        pull_all(&payer, self.max_chunk, self.parallel, Duration::ZERO).await?;
+                    total += pulled;
+                    if unknown {
+                        return Ok(total);
+                    }
+                    break self.max_chunk;
+                }
+                match payer.pay(amount).await? {
+                    Chunk::Paid => {
+                        total += amount;
+                        amount *= 2;
+                    }
+                    Chunk::Insufficient => break amount,
+                    Chunk::UnknownUid => return Ok(total),
+                }
+            };
+
+            while bound > 1 {
+                // the largest power of two below bound
+                let amount = 1i64 << (63 - (bound - 1).leading_zeros());
+                match payer.pay(amount).await? {
+                    Chunk::Paid => {
+                        total += amount;
+                        bound -= amount;
+                    }
+                    Chunk::Insufficient => bound = amount,
+                    Chunk::UnknownUid => break,
+                }
+            }
+            Ok(total)
+        })
+    }
+}
+
+pub static STRATEGY: LazyLock<Box<dyn DrainStrategy>> = LazyLock::new(|| {
+    let config = &GLOBAL_CONFIG.drain;
+    let (max_chunk, parallel) = (config.max_chunk.max(1), config.parallel.max(1));
+    match config.strategy {
+        StrategyKind::Halving => Box::new(Halving {
+            max_chunk,
+            parallel,
+        }),
+        StrategyKind::Exponential => Box::new(Exponential {
+            max_chunk,
+            parallel,
+        }),
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::Instant,
+    };
+
+    use super::*;
+
+    // SimUpstream is a fake fund service holding one account
+    struct SimUpstream {
+        balance: Mutex<i64>,
+        latency: Duration,
+        requests: AtomicU64,
+    }
+
+    impl Payer for SimUpstream {
+        fn pay(&self, amount: i64) -> BoxFuture<'_, Result<Chunk>> {
+            Box::pin(async move {
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                time::sleep(self.latency).await;
+                let mut balance = self.balance.lock().unwrap();
+                if *balance < amount {
+                    return Ok(Chunk::Insufficient);
+                }
+                *balance -= amount;
+                Ok(Chunk::Paid)
+            })
+        }
+    }
+
+    async fn simulate(strategy: &dyn DrainStrategy, balance: i64) -> (u64, Duration) {
+        let upstream = Arc::new(SimUpstream {
+            balance: Mutex::new(balance),
+            latency: Duration::from_millis(1),
+            requests: AtomicU64::new(0),
+        });
+        let start = Instant::now();
+        let drained = strategy.drain(upstream.clone()).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(drained, balance);
+        assert_eq!(*upstream.balance.lock().unwrap(), 0);
+        (upstream.requests.load(Ordering::Relaxed), elapsed)
+    }
+
+    #[tokio::test]
+    async fn test_strategies_drain_everything() {
+        let halving = Halving {
+            max_chunk: 1_000_000,
+            parallel: 30,
+        };
+        let exponential = Exponential {
+            max_chunk: 1_000_000,
+            parallel: 30,
+        };
+        for balance in [0, 1, 8891, 999_999, 1_000_093, 10_206_115, 25_000_001] {
+            let (halving_requests, halving_time) = simulate(&halving, balance).await;
+            let (exp_requests, exp_time) = simulate(&exponential, balance).await;
+            println!(
+                "balance {balance:>10}: halving {halving_requests:>4} requests {halving_time:>10.2?}, \
+                 exponential {exp_requests:>4} requests {exp_time:>10.2?}"
+            );
+            if balance < 1_000_000 {
+                assert!(exp_requests < halving_requests);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_uid_stops_the_drain() {
+        struct Unknown(AtomicU64);
+        impl Payer for Unknown {
+            fn pay(&self, _amount: i64) -> BoxFuture<'_, Result<Chunk>> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Ok(Chunk::UnknownUid) })
+            }
+        }
+        let strategies: [Box<dyn DrainStrategy>; 2] = [
+            Box::new(Halving {
+                max_chunk: 1024,
+                parallel: 1,
+            }),
+            Box::new(Exponential {
+                max_chunk: 1024,
+                parallel: 1,
+            }),
+        ];
+        for strategy in strategies {
+            let payer = Arc::new(Unknown(AtomicU64::new(0)));
+            assert_eq!(strategy.drain(payer.clone()).await.unwrap(), 0);
+            assert_eq!(payer.0.load(Ordering::Relaxed), 1);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{fmt, time::Duration};
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::db::error::EngineError;
+
+// AppError is every failure a handler can report, each variant has a
+// stable `code` clients can match on instead of parsing `msg`
+#[derive(Debug)]
+pub enum AppError {
+    InvalidBody(String),
+    // a missing or malformed X-KSY-* header
+    InvalidHeader(String),
+    // the caller could not be authenticated
+    Unauthorized(String),
+    // the caller is not allowed to call the endpoint
+    Forbidden(String),
+    // over a rate limit, the request may be retried after the duration
+    RateLimited(Duration),
+    InvalidAmount(String),
+    DuplicateRequest(String),
+    // a retry of a request that is still being processed
+    RequestInProgress,
+    // a request id reused with a different body
+    RequestConflict,
+    AccountNotFound(i64),
+    InsufficientBalance,
+    // self transfers, trades with system accounts and similar
+    InvalidTrade(String),
+    BatchPayNotFound(String),
+    // the account may not send money
+    AccountFrozen(i64),
+    AccountClosed(i64),
+    // closing an account that has money left needs a sweep target
+    BalanceNotZero(i64),
+    // closing an account a batch pay is still draining
+    AccountDraining(i64),
+    Upstream(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> i32 {
+        match self {
+            AppError::InvalidBody(_) => 1001,
+            AppError::InvalidAmount(_) => 1002,
+            AppError::DuplicateRequest(_) => 1003,
+            AppError::RequestInProgress => 1004,
+            AppError::RequestConflict => 1005,
+            AppError::InvalidHeader(_) => 1006,
+            AppError::Unauthorized(_) => 1007,
+            AppError::Forbidden(_) => 1008,
+            AppError::RateLimited(_) => 1009,
+            AppError::AccountNotFound(_) => 2001,
+            AppError::InsufficientBalance => 2002,
+            AppError::InvalidTrade(_) => 2003,
+            AppError::BatchPayNotFound(_) => 2004,
+            AppError::AccountFrozen(_) => 2005,
+            AppError::AccountClosed(_) => 2006,
+            AppError::BalanceNotZero(_) => 2007,
+            AppError::AccountDraining(_) => 2008,
+            AppError::Upstream(_) => 5001,
+            AppError::Internal(_) => 5000,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::RequestInProgress | AppError::RequestConflict => StatusCode::CONFLICT,
+            AppError::AccountNotFound(_) | AppError::BatchPayNotFound(_) => StatusCode::NOT_FOUND,
+            // the request is fine, the account is not in a state to take it
+            AppError::AccountFrozen(_)
+            | AppError::AccountClosed(_)
+            | AppError::BalanceNotZero(_)
+            | AppError::AccountDraining(_) => StatusCode::CONFLICT,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn with_request_id(self, request_id: &str) -> ApiError {
+        ApiError {
+            error: self,
+            request_id: request_id.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InvalidBody(msg) => write!(f, "invalid body: {msg}"),
+            AppError::InvalidHeader(msg) => write!(f, "invalid header: {msg}"),
+            AppError::Unauthorized(msg) => write!(f, "unauthorized: {msg}"),
+            AppError::Forbidden(msg) => write!(f, "forbidden: {msg}"),
+            AppError::RateLimited(retry_after) => {
+                write!(f, "too many requests, retry after {retry_after:.2?}")
+            }
+            AppError::InvalidAmount(msg) => write!(f, "invalid amount: {msg}"),
+            AppError::DuplicateRequest(msg) => write!(f, "{msg}"),
+            AppError::RequestInProgress => write!(f, "request is still being processed"),
+            AppError::RequestConflict => {
+                write!(f, "requestId was already used with a different body")
+            }
+            AppError::AccountNotFound(uid) => write!(f, "can not find the account {uid}"),
+            AppError::InsufficientBalance => write!(f, "insufficient balance"),
+            AppError::InvalidTrade(msg) => write!(f, "{msg}"),
+            AppError::BatchPayNotFound(id) => write!(f, "can not find the batch pay {id}"),
+            AppError::AccountFrozen(uid) => write!(f, "account {uid} is frozen"),
+            AppError::AccountClosed(uid) => write!(f, "account {uid} is closed"),
+            AppError::BalanceNotZero(uid) => write!(f, "account {uid} still has a balance"),
+            AppError::AccountDraining(uid) => {
+                write!(f, "a batch pay is still draining account {uid}")
+            }
+            AppError::Upstream(msg) => write!(f, "upstream failure: {msg}"),
+            AppError::Internal(msg) => write!(f, "internal error: {msg}"),
+        }
+    }
+}
+
+impl From<EngineError> for AppError {
+    fn from(err: EngineError) -> Self {
+        match err {
+            EngineError::AccountNotFound(uid) => AppError::AccountNotFound(uid),
+            EngineError::JobNotFound(id) => AppError::BatchPayNotFound(id),
+            EngineError::InsufficientBalance => AppError::InsufficientBalance,
+            EngineError::AccountFrozen(uid) => AppError::AccountFrozen(uid),
+            EngineError::AccountClosed(uid) => AppError::AccountClosed(uid),
+            EngineError::BalanceNotZero(uid) => AppError::BalanceNotZero(uid),
+            EngineError::AccountDraining(uid) => AppError::AccountDraining(uid),
+            EngineError::InvalidAmount(_) | EngineError::Overflow(_) => {
+                AppError::InvalidAmount(err.to_string())
+            }
+            EngineError::SelfTransfer | EngineError::SystemAccount(_) => {
+                AppError::InvalidTrade(err.to_string())
+            }
+            EngineError::Unbalanced | EngineError::Corrupted(_) | EngineError::Storage(_) => {
+                tracing::error!("engine failure: {}", err);
+                AppError::Internal(err.to_string())
+            }
+        }
+    }
+}
+
+// ApiError is an AppError bound to the request it failed, it renders as
+// the same {code, msg, requestId} envelope as successful responses
+#[derive(Debug)]
+pub struct ApiError {
+    pub error: AppError,
+    pub request_id: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    code: i32,
+    msg: String,
+    #[serde(rename = "requestId")]
+    request_id: &'a str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        self.error.status()
+    }
+
+    pub fn body(&self) -> Value {
+        json!(ErrorResponse {
+            code: self.error.code(),
+            msg: self.error.to_string(),
+            request_id: &self.request_id,
+        })
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        // Retry-After is in whole seconds, rounded up
+        if let AppError::RateLimited(retry_after) = self.error {
+            let seconds = retry_after.as_millis().div_ceil(1000).max(1);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds as u64));
+        }
+        response
+    }
+}
+
+pub trait ResultExt<T> {
+    fn with_request_id(self, request_id: &str) -> Result<T, ApiError>;
+}
+
+impl<T, E: Into<AppError>> ResultExt<T> for Result<T, E> {
+    fn with_request_id(self, request_id: &str) -> Result<T, ApiError> {
+        self.map_err(|err| err.into().with_request_id(request_id))
+    }
+}
+
+// parse_body decodes a JSON request body, reporting failures as InvalidBody
+pub fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, AppError> {
+    serde_json::from_str(body).map_err(|err| AppError::InvalidBody(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_envelope() {
+        let err: AppError = EngineError::InsufficientBalance.into();
+        let response = err.with_request_id("req-1").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], 2002);
+        assert_eq!(body["msg"], "insufficient balance");
+        assert_eq!(body["requestId"], "req-1");
+
+        let limited = AppError::RateLimited(Duration::from_millis(1200)).with_request_id("req-2");
+        let response = limited.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn test_parse_body() {
+        let err = parse_body::<Vec<i64>>("[1, \"a\"]").unwrap_err();
+        assert_eq!(err.code(), 1001);
+        let err: AppError = EngineError::Storage(anyhow::anyhow!("disk full")).into();
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,123 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap},
+};
+use uuid::Uuid;
+
+use crate::{
+    config::MissingRequestId,
+    error::{ApiError, AppError},
+    GLOBAL_CONFIG,
+};
+
+// the ids are part of what a request signature covers
+pub use crate::sign::{CALLER_ID_HEADER, REQUEST_ID_HEADER};
+
+const MAX_HEADER_LEN: usize = 128;
+
+// KsyHeaders are the validated X-KSY-* headers of an inbound request,
+// a missing request id is generated or rejected per `headers.missing_request_id`
+#[derive(Debug, Clone)]
+pub struct KsyHeaders {
+    pub request_id: String,
+    pub caller_id: Option<String>,
+}
+
+// RequiredKsyHeaders is KsyHeaders for handlers that deduplicate on the
+// request id, a missing one is always rejected
+#[derive(Debug, Clone)]
+pub struct RequiredKsyHeaders(pub KsyHeaders);
+
+fn header(headers: &HeaderMap, name: &str) -> Result<Option<String>, AppError> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    let invalid = |reason: &str| AppError::InvalidHeader(format!("{name} {reason}"));
+    let value = value
+        .to_str()
+        .map_err(|_| invalid("must be visible ASCII"))?;
+    if value.is_empty() || value.len() > MAX_HEADER_LEN {
+        return Err(invalid(&format!(
+            "must be 1 to {MAX_HEADER_LEN} characters"
+        )));
+    }
+    if !value.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(invalid("must be visible ASCII"));
+    }
+    Ok(Some(value.to_string()))
+}
+
+impl KsyHeaders {
+    fn parse(headers: &HeaderMap, policy: MissingRequestId) -> Result<Self, ApiError> {
+        let request_id =
+            header(headers, REQUEST_ID_HEADER).map_err(|err| err.with_request_id(""))?;
+        let caller_id = header(headers, CALLER_ID_HEADER)
+            .map_err(|err| err.with_request_id(request_id.as_deref().unwrap_or("")))?;
+        match (request_id, policy) {
+            (Some(request_id), _) => Ok(KsyHeaders {
+                request_id,
+                caller_id,
+            }),
+            (None, MissingRequestId::Generate) => Ok(KsyHeaders {
+                request_id: Uuid::new_v4().to_string(),
+                caller_id,
+            }),
+            (None, MissingRequestId::Reject) => Err(AppError::InvalidHeader(format!(
+                "{REQUEST_ID_HEADER} is required"
+            ))
+            .with_request_id("")),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for KsyHeaders {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        KsyHeaders::parse(&parts.headers, GLOBAL_CONFIG.headers.missing_request_id)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequiredKsyHeaders {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        KsyHeaders::parse(&parts.headers, MissingRequestId::Reject).map(RequiredKsyHeaders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let mut headers = HeaderMap::new();
+        let parsed = KsyHeaders::parse(&headers, MissingRequestId::Generate).unwrap();
+        assert!(!parsed.request_id.is_empty());
+        let err = KsyHeaders::parse(&headers, MissingRequestId::Reject).unwrap_err();
+        assert_eq!(
+            err.error.code(),
+            AppError::InvalidHeader(String::new()).code()
+        );
+
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-1"));
+        headers.insert(CALLER_ID_HEADER, HeaderValue::from_static("20004"));
+        let parsed = KsyHeaders::parse(&headers, MissingRequestId::Reject).unwrap();
+        assert_eq!(parsed.request_id, "req-1");
+        assert_eq!(parsed.caller_id.as_deref(), Some("20004"));
+
+        headers.insert(
+            REQUEST_ID_HEADER,
+            HeaderValue::from_bytes(&[0xe4, 0xbd, 0xa0]).unwrap(),
+        );
+        assert!(KsyHeaders::parse(&headers, MissingRequestId::Generate).is_err());
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("a b"));
+        assert!(KsyHeaders::parse(&headers, MissingRequestId::Generate).is_err());
+    }
+}
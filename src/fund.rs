@@ -1,247 +1,317 @@
-use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::{sync::Arc, time::Duration};
 
-use anyhow::Result;
-use awaitgroup::WaitGroup;
-use reqwest::{Client, StatusCode};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::{
-    sync::mpsc::{self, Sender},
-    task, time,
-};
+use tokio::{sync::Semaphore, task::JoinSet, time};
 use uuid::Uuid;
 
-use crate::GLOBAL_CONFIG;
-
-#[derive(Serialize)]
-struct GetFundJson {
-    #[serde(rename = "transactionId")]
-    transaction_id: String,
-    uid: i64,
-    amount: f64,
-}
+use crate::{
+    drain::{BoxFuture, Chunk, Payer, STRATEGY},
+    limit::UPSTREAM_IN_FLIGHT,
+    money::Money,
+    retry::{self, BREAKERS},
+    upstream::FundProvider,
+    GLOBAL_CONFIG,
+};
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct Fund {
     pub uid: i64,
-    pub amount: f64,
+    pub amount: Money,
 }
 
-#[derive(Deserialize)]
-struct GetFundResponse {
-    code: i32,
-    #[serde(rename = "requestId")]
-    request_id: String,
-    msg: String,
-    data: String,
+// Checkpoint hears about every get_pay transaction of a drain, so the
+// drain can be picked up again after a crash
+pub trait Checkpoint: Send + Sync {
+    // sending is called before a new transactionId goes out, it is not sent if this fails
+    fn sending<'a>(&'a self, transaction_id: &'a str, amount: i64) -> BoxFuture<'a, Result<()>>;
+    // unknown is called once if transactionId got no answer in time
+    fn unknown<'a>(&'a self, transaction_id: &'a str) -> BoxFuture<'a, ()>;
+    // settled is called once the upstream answered transactionId for good
+    fn settled<'a>(&'a self, transaction_id: &'a str, confirmed: bool) -> BoxFuture<'a, ()>;
 }
 
-// 不保证正确
-pub async fn get_pay(uid: i64, amount: i64, unique_id: String, tx: Sender<i32>) {
-    println!("GETPAY uid: {uid}, amount: {amount}, uniqueID: {unique_id}");
-    let data = GetFundJson {
-        transaction_id: unique_id,
-        uid,
-        amount: amount as f64 / 100.0,
-    };
-    let uuid = Uuid::new_v4().to_string();
-    let json_data = serde_json::json!(data);
-    let response = Client::new()
-        .post(&*GLOBAL_CONFIG.urls.get_pay)
-        .header("Content-Type", "application/json")
-        .header("X-KSY-REQUEST-ID", &uuid)
-        .header("X-KSY-KINGSTAR-ID", "20004")
-        .body(json_data.to_string())
-        .send()
-        .await;
-
-    if let Err(e) = response {
-        tracing::error!("{}", e.to_string());
-        let _ = tx.send(0).await;
-        return;
-    }
-    let response = response.unwrap();
-    let status = response.status();
-    let body = response.text().await;
-    if let Err(e) = body {
-        tracing::error!("{}", e.to_string());
-        let _ = tx.send(0).await;
-        return;
-    }
+// NoCheckpoint is for drains that do not need to survive a restart
+#[cfg(test)]
+pub struct NoCheckpoint;
 
-    // 打印响应体
-    // println!("Response status code: {}", status);
-    // println!("Response body: {}", body);
-
-    match status {
-        StatusCode::OK => {}
-        // StatusCode::GATEWAY_TIMEOUT => {
-        //     // return Err(anyhow!("Request failed with status code: {}", status))
-        //     tracing::error!("Request failed with status code: {}", status);
-        //     return;
-        // }
-        _ => {
-            // return Err(anyhow!("Error getting fund: {}", status))
-            let _ = tx.send(1).await;
-            return;
-        }
+#[cfg(test)]
+impl Checkpoint for NoCheckpoint {
+    fn sending<'a>(&'a self, _transaction_id: &'a str, _amount: i64) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
     }
 
-    let result = serde_json::from_str::<GetFundResponse>(&body.unwrap());
-    if let Err(e) = result {
-        tracing::error!("{}", e.to_string());
-        let _ = tx.send(0).await;
-        return;
+    fn unknown<'a>(&'a self, _transaction_id: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
     }
-    let result = result.unwrap();
-    if result.request_id != uuid {
-        // return Err(anyhow!("Error getting fund: {} {}", status, body));
-        let _ = tx.send(1).await;
-        return;
+
+    fn settled<'a>(&'a self, _transaction_id: &'a str, _confirmed: bool) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
     }
+}
 
-    let _ = tx.send(result.code).await;
+// init_funds seeds the fund service, the service never calls it itself,
+// it is there for tests and for setting up an upstream by hand
+#[allow(dead_code)]
+pub async fn init_funds(provider: &dyn FundProvider, list: Vec<Fund>) -> Result<()> {
+    retry::with_retry("init_funds", &retry::policy(), &BREAKERS.init_funds, || {
+        provider.init_funds(&list)
+    })
+    .await
 }
 
-pub async fn init_funds(list: Vec<Fund>) -> Result<()> {
-    let json_data = json!(list);
-    let response = Client::new()
-        .post(&*GLOBAL_CONFIG.urls.init_funds)
-        .header("Content-Type", "application/json")
-        .header("X-KSY-REQUEST-ID", "1")
-        .header("X-KSY-KINGSTAR-ID", "20004")
-        .body(json_data.to_string())
-        .send()
-        .await?;
-
-    let status = response.status();
-    let body = response.text().await?;
-
-    // 打印响应体
-    println!("Response status code: {}", status);
-    println!("Response body: {}", body);
-    Ok(())
+// FundPayer pulls chunks of one uid from the fund service, every
+// transaction is checkpointed before it is sent
+struct FundPayer {
+    provider: Arc<dyn FundProvider>,
+    uid: i64,
+    checkpoint: Arc<dyn Checkpoint>,
+    job_slots: Arc<Semaphore>,
 }
 
-pub async fn get_all_fund(uid: i64) -> Result<i64> {
-    let (mut pre, mut ans) = (500000i64, 0i64);
-    println!("before get all one amount");
-    ans += get_all_one_amount(uid, 1000000, 30).await;
-    while pre >= 1 {
-        ans += get_all_one_amount(uid, pre, 2).await;
-        pre /= 2;
+impl Payer for FundPayer {
+    // a chunk that never got an answer is left in flight in the checkpoint
+    fn pay(&self, amount: i64) -> BoxFuture<'_, Result<Chunk>> {
+        Box::pin(async move {
+            let unique_id = Uuid::new_v4().to_string();
+            self.checkpoint.sending(&unique_id, amount).await?;
+            let code = send_until_settled(
+                &self.provider,
+                self.uid,
+                amount,
+                &unique_id,
+                &*self.checkpoint,
+                &self.job_slots,
+            )
+            .await?;
+            self.checkpoint.settled(&unique_id, code == 200).await;
+            Ok(match code {
+                200 => Chunk::Paid,
+                501 => Chunk::Insufficient,
+                _ => Chunk::UnknownUid,
+            })
+        })
     }
+}
 
-    Ok(ans)
+pub async fn get_all_fund(
+    provider: &Arc<dyn FundProvider>,
+    uid: i64,
+    checkpoint: Arc<dyn Checkpoint>,
+    job_slots: Arc<Semaphore>,
+) -> Result<i64> {
+    println!("before get all one amount");
+    let payer = FundPayer {
+        provider: provider.clone(),
+        uid,
+        checkpoint,
+        job_slots,
+    };
+    STRATEGY.drain(Arc::new(payer)).await
 }
 
-async fn get_all_one_amount(uid: i64, amount: i64, max_parallel: usize) -> i64 {
-    let ans = Arc::new(Mutex::new(0));
-    let (tx_done, mut rx_done) = mpsc::channel(500);
-    let mut wg = WaitGroup::new();
-    for i in 1..=max_parallel {
-        if let Ok(_) = rx_done.try_recv() {
-            break;
+type Attempts = JoinSet<Result<i32>>;
+
+// wait_for_answer waits up to `wait` for a final answer to any attempt
+// still out there, attempts that fail are dropped and their error kept.
+// With `until_idle` it gives up as soon as no attempt is left
+async fn wait_for_answer(
+    attempts: &mut Attempts,
+    wait: Duration,
+    until_idle: bool,
+    last_error: &mut anyhow::Error,
+) -> Option<i32> {
+    let deadline = time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        if until_idle && attempts.is_empty() {
+            return None;
         }
-        if max_parallel > 2 {
-            if i < 30 && i != 1 {
-                time::sleep(Duration::from_millis(10)).await;
-            }
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok(Ok(code @ (200 | 501 | 404))) => return Some(code),
+                Ok(Ok(code)) => *last_error = anyhow!("get_pay returned {}", code),
+                Ok(Err(err)) => *last_error = err,
+                Err(err) => *last_error = err.into(),
+            },
+            _ = &mut deadline => return None,
         }
-        let ans = ans.clone();
-        let tx_done = tx_done.clone();
-        let worker = wg.worker();
-        task::spawn(async move {
-            *ans.lock().unwrap() += singal_get_pay(uid, amount).await;
-            tx_done.send(true).await.unwrap();
-            worker.done();
-        });
     }
-    wg.wait().await;
-    *ans.to_owned().lock().unwrap()
 }
 
-async fn singal_get_pay(uid: i64, amount: i64) -> i64 {
+// send_until_settled sends the same transactionId until the upstream gives
+// a final answer, one of 200, 501 (insufficient) or 404 (unknown uid).
+// A send that gets no answer within request_timeout leaves the outcome
+// unknown, it is reported to the checkpoint once and the same
+// transactionId is sent again. Earlier sends keep running, so an answer
+// that arrives late still settles the transaction, and since the upstream
+// never pays a transactionId twice it does not matter which send answers.
+// If the retry budget is used up the transaction stays unknown and is
+// resolved by sending it again later. A slot of the job is taken before one
+// of the whole service, so a job at its limit does not hold up the others
+pub async fn send_until_settled(
+    provider: &Arc<dyn FundProvider>,
+    uid: i64,
+    amount: i64,
+    unique_id: &str,
+    checkpoint: &dyn Checkpoint,
+    job_slots: &Semaphore,
+) -> Result<i32> {
+    // waiting for a slot does not count against the timeouts
+    let _job_permit = job_slots
+        .acquire()
+        .await
+        .expect("the job semaphore is never closed");
+    let _permit = UPSTREAM_IN_FLIGHT
+        .acquire()
+        .await
+        .expect("the upstream semaphore is never closed");
     let config = &*GLOBAL_CONFIG;
     let timeout = Duration::from_millis(config.server.request_timeout as u64);
-    let mut ans = 0i64;
-    let mut unique_id = Uuid::new_v4().to_string();
+    let breaker = &BREAKERS.get_pay;
+    let mut budget = retry::policy().budget();
+    let mut attempts = Attempts::new();
+    let mut last_error = anyhow!("circuit breaker is open");
+    let mut unknown = false;
 
+    // 超时/或其他原因重试
     loop {
-        // 超时/或其他原因重试
-        let (tx, mut rx) = mpsc::channel(100);
-        let tx_clone = tx.clone();
-        let unique_id_1 = unique_id.clone();
-        task::spawn(get_pay(uid, amount, unique_id_1, tx_clone));
-
-        tokio::select! {
-            Some(code) = rx.recv() => {
-                match code {
-                    200 => {
-                        ans += amount;
-                        unique_id = Uuid::new_v4().to_string();
-                        continue;
-                    }
-                    501 => return ans,
-                    404 => return 0,
-                    _ => continue,
+        if breaker.allow() {
+            let (provider, transaction_id) = (provider.clone(), unique_id.to_string());
+            attempts.spawn(async move { provider.get_pay(uid, amount, &transaction_id).await });
+            match wait_for_answer(&mut attempts, timeout, true, &mut last_error).await {
+                Some(code) => {
+                    breaker.success();
+                    return Ok(code);
                 }
-            },
-            _ = time::sleep(timeout) => {
-                // println!("Timeout!");
-                if let Some(_) = rx.recv().await {}
-                continue;
+                None => breaker.failure(),
+            }
+            if !attempts.is_empty() && !unknown {
+                unknown = true;
+                last_error = anyhow!("timed out");
+                checkpoint.unknown(unique_id).await;
             }
         }
+        let Some(delay) = budget.retry() else {
+            break;
+        };
+        if let Some(code) = wait_for_answer(&mut attempts, delay, false, &mut last_error).await {
+            breaker.success();
+            return Ok(code);
+        }
     }
+
+    // one more window for the sends that are still out there
+    if let Some(code) = wait_for_answer(&mut attempts, timeout, true, &mut last_error).await {
+        breaker.success();
+        return Ok(code);
+    }
+    Err(anyhow!(
+        "get_pay {} gave up after {} attempts: {:#}",
+        unique_id,
+        budget.attempts(),
+        last_error
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{limit::job_in_flight, upstream::mock::MockProvider};
 
-    // #[tokio::test]
-    // async fn test_get_pay() {
-    //     let uid = 600001i64;
-    //     let amount = 1i64;
-    //     let res = get_pay(uid, amount, "aaaaa".into()).await;
-    //     dbg!(res.unwrap());
-    // }
-
-    #[tokio::test]
-    async fn test_get_all_fund() {
-        let funds = vec![
+    fn funds() -> Vec<Fund> {
+        vec![
             Fund {
                 uid: 600001,
-                amount: 88.91,
+                amount: Money::from_cents(8891),
             },
             Fund {
                 uid: 600002,
-                amount: 10000.93,
+                amount: Money::from_cents(1000093),
             },
-        ];
-        let res = init_funds(funds).await;
-        dbg!(res.unwrap());
-        let res = get_all_fund(600004).await;
-        dbg!(res.unwrap());
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_get_all_fund() {
+        let mock = Arc::new(MockProvider::new().with_delay(Duration::from_millis(2)));
+        let provider: Arc<dyn FundProvider> = mock.clone();
+        init_funds(&*provider, funds()).await.unwrap();
+        for (uid, amount) in [(600001, 8891), (600002, 1000093), (600004, 0)] {
+            let res = get_all_fund(&provider, uid, Arc::new(NoCheckpoint), job_in_flight()).await;
+            assert_eq!(res.unwrap(), amount);
+        }
+        assert_eq!(mock.balance(600001), Some(0));
+        assert_eq!(mock.balance(600002), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_get_all_fund_with_timeouts() {
+        let mock = Arc::new(
+            MockProvider::new()
+                .with_balance(600001, 8891)
+                .with_timeouts(0.2),
+        );
+        let provider: Arc<dyn FundProvider> = mock.clone();
+        let res = get_all_fund(&provider, 600001, Arc::new(NoCheckpoint), job_in_flight()).await;
+        assert_eq!(res.unwrap(), 8891);
+        assert_eq!(mock.balance(600001), Some(0));
+    }
+
+    // Recorder keeps the checkpoint calls of a drain
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl Checkpoint for Recorder {
+        fn sending<'a>(
+            &'a self,
+            transaction_id: &'a str,
+            _amount: i64,
+        ) -> BoxFuture<'a, Result<()>> {
+            let event = format!("sending {transaction_id}");
+            Box::pin(async move {
+                self.0.lock().unwrap().push(event);
+                Ok(())
+            })
+        }
+
+        fn unknown<'a>(&'a self, transaction_id: &'a str) -> BoxFuture<'a, ()> {
+            let event = format!("unknown {transaction_id}");
+            Box::pin(async move { self.0.lock().unwrap().push(event) })
+        }
+
+        fn settled<'a>(&'a self, transaction_id: &'a str, confirmed: bool) -> BoxFuture<'a, ()> {
+            let event = format!("settled {transaction_id} {confirmed}");
+            Box::pin(async move { self.0.lock().unwrap().push(event) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_late_answer_settles_once() {
+        // every answer arrives after request_timeout
+        let late_by = Duration::from_millis(GLOBAL_CONFIG.server.request_timeout as u64 + 200);
+        let mock = Arc::new(
+            MockProvider::new()
+                .with_balance(600001, 100)
+                .with_late_answers(1.0, late_by),
+        );
+        let provider: Arc<dyn FundProvider> = mock.clone();
+        let (recorder, slots) = (Recorder::default(), job_in_flight());
+        let code = send_until_settled(&provider, 600001, 60, "tx-1", &recorder, &slots).await;
+        assert_eq!(code.unwrap(), 200);
+        assert_eq!(*recorder.0.lock().unwrap(), ["unknown tx-1"]);
+        assert!(mock.requests() >= 2);
+
+        // resolving it again later gets the same answer and pays nothing more
+        let code = send_until_settled(&provider, 600001, 60, "tx-1", &NoCheckpoint, &slots).await;
+        assert_eq!(code.unwrap(), 200);
+        assert_eq!(mock.balance(600001), Some(40));
     }
 
     #[tokio::test]
     async fn test_init_fund() {
-        let funds = vec![
-            Fund {
-                uid: 600001,
-                amount: 88.91,
-            },
-            Fund {
-                uid: 600002,
-                amount: 10000.93,
-            },
-        ];
-        let res = init_funds(funds).await;
-        dbg!(res.unwrap());
+        let mock = MockProvider::new();
+        init_funds(&mock, funds()).await.unwrap();
+        assert_eq!(mock.balance(600002), Some(1000093));
     }
 }
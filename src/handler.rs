@@ -1,21 +1,30 @@
-use std::time::Duration;
-
-use awaitgroup::WaitGroup;
 use axum::{
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::StatusCode,
+    response::{IntoResponse, Response},
     Json,
 };
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::task;
-use uuid::Uuid;
 
 use crate::{
-    db,
-    fund::{get_all_fund, Fund},
-    uuid_cache, GLOBAL_CONFIG,
+    batch_job,
+    db::{
+        self,
+        account::AccountStatus,
+        batch::{JobState, UidState},
+        idempotency::{IdempotencyRecord, KeyScope, StoredResponse},
+        journal::{self, EntryKind},
+        ledger,
+    },
+    error::{parse_body, ApiError, AppError, ResultExt},
+    extract::{KsyHeaders, RequiredKsyHeaders},
+    fund::Fund,
+    limit,
+    money::Money,
+    upstream,
+    uuid_cache::{self, Claim},
+    GLOBAL_CONFIG,
 };
 
 #[derive(Deserialize, Serialize)]
@@ -35,105 +44,271 @@ struct QueryUserAmountDataResponse {
     data: Vec<Fund>,
 }
 
+#[derive(Deserialize)]
+pub struct AccountHistoryJson {
+    uid: i64,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+const MAX_HISTORY_LIMIT: usize = 100;
+
+fn default_history_limit() -> usize {
+    20
+}
+
+#[derive(Serialize)]
+struct HistoryEntryJson {
+    id: u64,
+    timestamp: u64,
+    kind: EntryKind,
+    counterparty: Option<i64>,
+    amount: Money,
+    balance: Money,
+}
+
+#[derive(Serialize)]
+struct AccountHistoryData {
+    uid: i64,
+    total: usize,
+    entries: Vec<HistoryEntryJson>,
+}
+
 #[derive(Serialize)]
-struct FinishJson {
+struct AccountHistoryDataResponse {
+    code: i32,
+    msg: String,
+    #[serde(rename = "requestId")]
+    request_id: String,
+    data: AccountHistoryData,
+}
+
+#[derive(Deserialize)]
+pub struct BatchPayStatusJson {
     #[serde(rename = "batchPayId")]
     batch_pay_id: String,
 }
 
+#[derive(Serialize)]
+struct UidProgressJson {
+    uid: i64,
+    state: UidState,
+    collected: Money,
+    credited: Money,
+    #[serde(rename = "inFlight")]
+    in_flight: usize,
+    // in flight transactions whose outcome is not known yet
+    unknown: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchPayStatusData {
+    batch_pay_id: String,
+    state: JobState,
+    created_at: u64,
+    finished_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    collected: Money,
+    failed: Vec<i64>,
+    uids: Vec<UidProgressJson>,
+}
+
+#[derive(Serialize)]
+struct BatchPayStatusDataResponse {
+    code: i32,
+    msg: String,
+    #[serde(rename = "requestId")]
+    request_id: String,
+    data: BatchPayStatusData,
+}
+
 #[derive(Serialize, Deserialize)]
 struct UserTradeJson {
     #[serde(rename = "sourceUid")]
     source_uid: i64,
     #[serde(rename = "targetUid")]
     target_uid: i64,
-    amount: f64,
+    amount: Money,
+}
+
+#[derive(Deserialize)]
+pub struct AccountJson {
+    uid: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CloseAccountJson {
+    uid: i64,
+    // where the balance left in the account goes, required unless it is empty
+    #[serde(rename = "sweepTo", default)]
+    sweep_to: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AccountStatusData {
+    uid: i64,
+    status: AccountStatus,
+}
+
+#[derive(Serialize)]
+struct AccountStatusDataResponse {
+    code: i32,
+    msg: String,
+    #[serde(rename = "requestId")]
+    request_id: String,
+    data: AccountStatusData,
+}
+
+// check_user_uids rejects system accounts, they are not balances of any user
+fn check_user_uids(uids: &[i64]) -> Result<(), AppError> {
+    match uids.iter().find(|uid| ledger::is_system_account(**uid)) {
+        Some(uid) => Err(AppError::InvalidBody(format!(
+            "uid {uid} is a system account"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn ok_response(request_id: String) -> Json<serde_json::Value> {
+    Json(json!({"msg": "ok", "code": 200, "requestId": request_id}))
 }
 
 pub async fn batch_pay(
-    header: HeaderMap,
-    Json(body): Json<BatchPayJson>,
-) -> anyhow::Result<impl IntoResponse, (StatusCode, String)> {
-    let time_start = tokio::time::Instant::now();
+    KsyHeaders {
+        request_id,
+        caller_id,
+    }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: BatchPayJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    check_user_uids(&body.uids).with_request_id(&request_id)?;
+    if GLOBAL_CONFIG.urls.get_pay.is_empty() {
+        return Err(
+            AppError::Upstream("fund service is not configured".to_string())
+                .with_request_id(&request_id),
+        );
+    }
+    // turned away before the id is taken, so the same batchPayId can come back later
+    let permit = limit::admit_batch_job()
+        .map_err(|retry_after| AppError::RateLimited(retry_after).with_request_id(&request_id))?;
     let batch_pay_id = body.batch_pay_id.to_owned();
-    if !uuid_cache::check_and_add_batch_pay(batch_pay_id) {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            json!({"error": "batchPayId already exist"}).to_string(),
-        ));
+    if !uuid_cache::check_and_add_batch_pay(batch_pay_id.clone()) {
+        return Err(
+            AppError::DuplicateRequest("batchPayId already exist".to_string())
+                .with_request_id(&request_id),
+        );
+    }
+    // make the id and the job durable before any money is pulled from upstream
+    let key = IdempotencyRecord::batch_pay(&batch_pay_id, journal::now_millis());
+    if let Err(err) = batch_job::start(&batch_pay_id, &body.uids, key).await {
+        uuid_cache::release_batch_pay(&batch_pay_id);
+        return Err(AppError::from(err).with_request_id(&request_id));
     }
 
+    tracing::info!(
+        "batch pay {} accepted from caller {}",
+        batch_pay_id,
+        caller_id.as_deref().unwrap_or("-")
+    );
     // 开一个异步任务
-    task::spawn(do_batch_pay(body, time_start));
-    let request_id = match header.get("X-KSY-REQUEST-ID") {
-        Some(value) => value.to_str().unwrap().to_string(),
-        None => "".to_string(),
-    };
+    task::spawn(async move {
+        batch_job::run(upstream::PROVIDER.clone(), batch_pay_id).await;
+        drop(permit);
+    });
 
-    Ok((
-        StatusCode::OK,
-        Json(json!({"msg": "ok", "code": 200, "requestId": request_id})),
-    ))
+    Ok((StatusCode::OK, ok_response(request_id)))
 }
 
-pub async fn user_trade(header: HeaderMap, body_raw: String) -> impl IntoResponse {
-    let request_id = match header.get("X-KSY-REQUEST-ID") {
-        Some(value) => value.to_str().unwrap().to_string(),
-        None => "".to_string(),
-    };
-    if !uuid_cache::check_and_add_trade(request_id.clone()) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "requestId already exist"})),
-        )
-            .into_response();
+// user_trade is idempotent on X-KSY-REQUEST-ID: a retry with the same body
+// gets the response of the first attempt back instead of trading twice,
+// so the id is required rather than generated
+pub async fn user_trade(
+    RequiredKsyHeaders(KsyHeaders { request_id, .. }): RequiredKsyHeaders,
+    body_raw: String,
+) -> Response {
+    let fingerprint = uuid_cache::fingerprint(body_raw.as_bytes());
+    match uuid_cache::claim_trade(&request_id, fingerprint) {
+        Claim::New => {}
+        Claim::Replay(response) => return response.into_response(),
+        Claim::InProgress => {
+            return AppError::RequestInProgress
+                .with_request_id(&request_id)
+                .into_response()
+        }
+        Claim::Conflict => {
+            return AppError::RequestConflict
+                .with_request_id(&request_id)
+                .into_response()
+        }
     }
-    let body: UserTradeJson = match serde_json::from_str(&body_raw) {
-        Ok(body) => body,
-        Err(_) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(json!({
-                    "error": "Invalid JSON"
-                })),
-            )
-                .into_response();
+
+    let success = StoredResponse {
+        status: StatusCode::OK.as_u16(),
+        body: ok_response(request_id.clone()).0,
+    };
+    let key = IdempotencyRecord {
+        scope: KeyScope::Trade,
+        key: request_id.clone(),
+        fingerprint,
+        timestamp: journal::now_millis(),
+        outcome: Some(success.clone()),
+    };
+    let response = match do_user_trade(&body_raw, key.clone()).await {
+        Ok(()) => success,
+        Err(err) => {
+            let err = err.with_request_id(&request_id);
+            if err.status().is_server_error() {
+                // nothing happened, let the client retry with the same id
+                uuid_cache::release_trade(&request_id);
+                return err.into_response();
+            }
+            let failure = StoredResponse {
+                status: err.status().as_u16(),
+                body: err.body(),
+            };
+            // no money moved, if this is lost the retry is simply evaluated again
+            let key = IdempotencyRecord {
+                outcome: Some(failure.clone()),
+                ..key
+            };
+            if let Err(err) = db::api::remember(key).await {
+                tracing::warn!("failed to persist outcome of {}: {}", request_id, err);
+            }
+            failure
         }
     };
+    uuid_cache::complete_trade(&request_id, response.clone());
+    response.into_response()
+}
 
-    if let Err(err) = db::api::transfer(
-        body.source_uid,
-        body.target_uid,
-        (body.amount * 100.0).round() as i64,
-    ) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": err.to_string()})),
-        )
-            .into_response();
-    }
-
-    (
-        StatusCode::OK,
-        Json(json!({"msg": "ok", "code": 200, "requestId": request_id})),
-    )
-        .into_response()
+// do_user_trade applies the trade and persists its idempotency key in one step
+async fn do_user_trade(body_raw: &str, key: IdempotencyRecord) -> Result<(), AppError> {
+    let body: UserTradeJson = parse_body(body_raw)?;
+    db::api::transfer_once(body.source_uid, body.target_uid, body.amount.cents(), key).await?;
+    Ok(())
 }
 
-pub async fn query_user_amount(header: HeaderMap, Json(body): Json<Vec<i64>>) -> impl IntoResponse {
+pub async fn query_user_amount(
+    KsyHeaders { request_id, .. }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: Vec<i64> = parse_body(&body_raw).with_request_id(&request_id)?;
+    check_user_uids(&body).with_request_id(&request_id)?;
     let mut data = vec![];
     for uid in body {
         let amount = db::api::get_balance(uid).unwrap_or(0);
         data.push(Fund {
             uid,
-            amount: amount as f64 / 100.0,
+            amount: Money::from_cents(amount),
         });
     }
-    let request_id = match header.get("X-KSY-REQUEST-ID") {
-        Some(value) => value.to_str().unwrap().to_string(),
-        None => "".to_string(),
-    };
-    (
+    Ok((
         StatusCode::OK,
         Json(json!(QueryUserAmountDataResponse {
             code: 200,
@@ -141,276 +316,340 @@ pub async fn query_user_amount(header: HeaderMap, Json(body): Json<Vec<i64>>) ->
             request_id,
             data,
         })),
-    )
+    ))
 }
 
-pub async fn batch_pay_finish(req_uuid: String, request_id: String) -> i32 {
-    let data = FinishJson {
-        batch_pay_id: request_id.clone(),
-    };
-    let json_data = json!(data);
-
-    let response = Client::new()
-        .post(&*GLOBAL_CONFIG.urls.batch_pay_finish)
-        .body(json_data.to_string())
-        .header("X-KSY-REQUEST-ID", req_uuid.clone())
-        .header("X-KSY-KINGSTAR-ID", "20004")
-        .send()
-        .await;
-    match response {
-        Ok(response) => {
-            let status_code = response.status();
-            // match response.text().await {
-            //     Ok(body) => {
-            //         println!("Response status code: {}", status_code);
-            //         println!("Response body: {}", body);
-            //         return status_code.as_u16() as i32;
-            //     }
-            //     Err(err) => {
-            //         tracing::error!("Error reading response body: {}", err);
-            //         return 0;
-            //     }
-            // }
-            return status_code.as_u16() as i32;
-        }
-        Err(err) => {
-            tracing::error!("Error sending requesst: {}", err.to_string());
-            return 0;
-        }
+pub async fn account_history(
+    KsyHeaders { request_id, .. }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: AccountHistoryJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    if body.limit == 0 || body.limit > MAX_HISTORY_LIMIT {
+        return Err(
+            AppError::InvalidBody(format!("limit must be 1 to {MAX_HISTORY_LIMIT}"))
+                .with_request_id(&request_id),
+        );
     }
+    let page = db::api::history(body.uid, body.offset, body.limit).with_request_id(&request_id)?;
+    let entries = page
+        .entries
+        .into_iter()
+        .map(|entry| HistoryEntryJson {
+            id: entry.id,
+            timestamp: entry.timestamp,
+            kind: entry.kind,
+            counterparty: entry.counterparty,
+            amount: Money::from_cents(entry.amount),
+            balance: Money::from_cents(entry.balance),
+        })
+        .collect();
+
+    Ok((
+        StatusCode::OK,
+        Json(json!(AccountHistoryDataResponse {
+            code: 200,
+            msg: "ok".to_string(),
+            request_id,
+            data: AccountHistoryData {
+                uid: body.uid,
+                total: page.total,
+                entries,
+            },
+        })),
+    ))
 }
 
-async fn do_batch_pay(body: BatchPayJson, time_start: tokio::time::Instant) {
-    pay_funds(body.uids).await;
-    println!("pay_funds use time: {}", time_start.elapsed().as_secs_f64());
-    // call batch_pay_finish when all user finish
-    let uuid: String = Uuid::new_v4().to_string();
-    loop {
-        match tokio::time::timeout(
-            Duration::from_millis(600),
-            batch_pay_finish(uuid.clone(), body.batch_pay_id.clone()),
-        )
+pub async fn batch_pay_status(
+    KsyHeaders { request_id, .. }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: BatchPayStatusJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    let job = batch_job::get(&body.batch_pay_id).ok_or_else(|| {
+        AppError::BatchPayNotFound(body.batch_pay_id.clone()).with_request_id(&request_id)
+    })?;
+    let data = BatchPayStatusData {
+        collected: Money::from_cents(job.collected()),
+        failed: job.failed(),
+        batch_pay_id: job.batch_pay_id,
+        state: job.state,
+        created_at: job.created_at,
+        finished_at: job.finished_at,
+        error: job.error,
+        uids: job
+            .uids
+            .into_iter()
+            .map(|(uid, progress)| UidProgressJson {
+                uid,
+                state: progress.state,
+                collected: Money::from_cents(progress.collected),
+                credited: Money::from_cents(progress.credited),
+                in_flight: progress.in_flight.len(),
+                unknown: progress.unknown.len(),
+                error: progress.error,
+            })
+            .collect(),
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(json!(BatchPayStatusDataResponse {
+            code: 200,
+            msg: "ok".to_string(),
+            request_id,
+            data,
+        })),
+    ))
+}
+
+pub async fn idempotency_stats(KsyHeaders { request_id, .. }: KsyHeaders) -> impl IntoResponse {
+    Json(json!({
+        "msg": "ok",
+        "code": 200,
+        "requestId": request_id,
+        "data": {
+            "batchPay": uuid_cache::batch_pay_stats(),
+            "trade": uuid_cache::trade_stats(),
+        },
+    }))
+}
+
+// account_status_response answers an account admin request with the
+// status uid ended up in
+fn account_status_response(request_id: String, uid: i64) -> Result<impl IntoResponse, ApiError> {
+    let status = db::api::account_status(uid).with_request_id(&request_id)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(AccountStatusDataResponse {
+            code: 200,
+            msg: "ok".to_string(),
+            request_id,
+            data: AccountStatusData { uid, status },
+        })),
+    ))
+}
+
+// open_account opens an empty account, opening one that exists is a no-op
+pub async fn open_account(
+    KsyHeaders {
+        request_id,
+        caller_id,
+    }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: AccountJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    db::api::open_account(body.uid)
         .await
-        {
-            Ok(code) => {
-                if code == 200 {
-                    println!("use time: {}", time_start.elapsed().as_secs_f64());
-                    return;
-                } else {
-                    continue;
-                }
-            }
-            // 超时重试
-            Err(_) => {
-                continue;
-            }
-        };
-    }
+        .with_request_id(&request_id)?;
+    tracing::info!(
+        "account {} opened by caller {}",
+        body.uid,
+        caller_id.as_deref().unwrap_or("-")
+    );
+    account_status_response(request_id, body.uid)
 }
 
-async fn pay_funds(uids: Vec<i64>) {
-    let mut wg = WaitGroup::new();
-    for uid in uids {
-        let worker = wg.worker();
-        task::spawn(async move {
-            let amount = get_all_fund(uid).await;
-            if let Ok(amount) = amount {
-                // let start = Instant::now();
-                db::api::add_money(uid, amount);
-                // println!(
-                //     "uid: {}, add money: {}, use time: {}ms",
-                //     uid,
-                //     amount,
-                //     start.elapsed().as_millis()
-                // )
-            }
-            worker.done();
-        });
-    }
+pub async fn freeze_account(
+    KsyHeaders {
+        request_id,
+        caller_id,
+    }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: AccountJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    db::api::freeze_account(body.uid)
+        .await
+        .with_request_id(&request_id)?;
+    tracing::info!(
+        "account {} frozen by caller {}",
+        body.uid,
+        caller_id.as_deref().unwrap_or("-")
+    );
+    account_status_response(request_id, body.uid)
+}
+
+pub async fn unfreeze_account(
+    KsyHeaders {
+        request_id,
+        caller_id,
+    }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: AccountJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    db::api::unfreeze_account(body.uid)
+        .await
+        .with_request_id(&request_id)?;
+    tracing::info!(
+        "account {} unfrozen by caller {}",
+        body.uid,
+        caller_id.as_deref().unwrap_or("-")
+    );
+    account_status_response(request_id, body.uid)
+}
 
-    wg.wait().await;
-    // println!("{:?}", db::api::get_all_balance());
+// close_account closes an account for good, a balance left in it is
+// moved to sweepTo in the same step
+pub async fn close_account(
+    KsyHeaders {
+        request_id,
+        caller_id,
+    }: KsyHeaders,
+    body_raw: String,
+) -> Result<impl IntoResponse, ApiError> {
+    let body: CloseAccountJson = parse_body(&body_raw).with_request_id(&request_id)?;
+    db::api::close_account(body.uid, body.sweep_to)
+        .await
+        .with_request_id(&request_id)?;
+    tracing::info!(
+        "account {} closed by caller {}, swept to {:?}",
+        body.uid,
+        caller_id.as_deref().unwrap_or("-"),
+        body.sweep_to
+    );
+    account_status_response(request_id, body.uid)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
-
-    use reqwest::Client;
-    use serde_json::json;
-    use tokio::time::Instant;
+    use axum::body::to_bytes;
+    use serde_json::Value;
     use uuid::Uuid;
 
-    use crate::fund::{init_funds, Fund};
+    use super::*;
 
-    use super::{BatchPayJson, UserTradeJson};
+    fn headers() -> KsyHeaders {
+        KsyHeaders {
+            request_id: Uuid::new_v4().to_string(),
+            caller_id: None,
+        }
+    }
 
-    #[tokio::test]
-    async fn test_batch_pay() {
-        let funds = vec![
-            Fund {
-                uid: 100001,
-                amount: 88.91,
-            },
-            Fund {
-                uid: 100042,
-                amount: 10000.93,
-            },
-            Fund {
-                uid: 403131,
-                amount: 2345.35,
-            },
-            Fund {
-                uid: 100052,
-                amount: 88.93,
-            },
-        ];
-        let res = init_funds(funds).await;
-        dbg!(res.unwrap());
+    fn new_uid() -> i64 {
+        (Uuid::new_v4().as_u128() % 1_000_000_000) as i64 + 2_000_000_000
     }
 
-    #[tokio::test]
-    async fn test_batch_pay_once() {
-        let funds = vec![Fund {
-            uid: 100001,
-            amount: 1001.53,
-        }];
-        init_funds(funds.clone()).await.unwrap();
-        let mut uids = vec![];
-        for f in funds {
-            uids.push(f.uid);
-        }
-        pay_funds_api(uids).await;
+    async fn call(response: impl IntoResponse) -> (StatusCode, Value) {
+        let response = response.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
     }
 
     #[tokio::test]
-    async fn test_batch_pay_from_file() {
-        let funds: Vec<Fund> = match File::open("testfile/initFund100.json") {
-            Ok(file) => serde_json::from_reader(file).unwrap(),
-            Err(err) => {
-                eprintln!("Error reading file: {}", err);
-                return;
-            }
-        };
-        init_funds(funds.clone()).await.unwrap();
-        // pay all funds
-        let mut uids = vec![];
-        for f in funds {
-            uids.push(f.uid);
-        }
-        pay_funds_api(uids).await;
+    async fn test_batch_pay_status() {
+        let body = json!({"batchPayId": "no-such-job"}).to_string();
+        let (status, body) = call(batch_pay_status(headers(), body).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::NOT_FOUND, &json!(2004))
+        );
+
+        let (batch_pay_id, uid) = (Uuid::new_v4().to_string(), new_uid());
+        let key = IdempotencyRecord::batch_pay(&batch_pay_id, journal::now_millis());
+        batch_job::start(&batch_pay_id, &[uid], key).await.unwrap();
+        let body = json!({ "batchPayId": batch_pay_id }).to_string();
+        let (status, body) = call(batch_pay_status(headers(), body).await).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["data"]["state"], "draining");
+        assert_eq!(body["data"]["uids"][0]["uid"], uid);
     }
 
     #[tokio::test]
-    async fn test_user_trade_big() {
-        let funds: Vec<Fund> = match File::open("testfile/initBigFund100.json") {
-            Ok(file) => serde_json::from_reader(file).unwrap(),
-            Err(err) => {
-                eprintln!("Error reading file: {}", err);
-                return;
-            }
+    async fn test_account_history() {
+        let uid = new_uid();
+        let history = |body: Value| async move {
+            call(account_history(headers(), body.to_string()).await).await
         };
-        init_funds(funds.clone()).await.unwrap();
-        // pay all funds
-        let mut uids = vec![];
-        for f in funds.clone() {
-            uids.push(f.uid);
+        let (status, body) = history(json!({ "uid": uid })).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::NOT_FOUND, &json!(2001))
+        );
+
+        db::api::open_account(uid).await.unwrap();
+        let (status, body) = history(json!({ "uid": uid })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["data"]["total"], 0);
+        for limit in [0, MAX_HISTORY_LIMIT + 1] {
+            let (status, body) = history(json!({"uid": uid, "limit": limit})).await;
+            assert_eq!(
+                (status, &body["code"]),
+                (StatusCode::BAD_REQUEST, &json!(1001))
+            );
         }
-        pay_funds_api(uids).await;
+        let (status, _) = history(json!({"uid": uid, "offset": -1})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
     }
 
     #[tokio::test]
-    async fn test_user_trade() {
-        let funds: Vec<Fund> = match File::open("testfile/initFund100.json") {
-            Ok(file) => serde_json::from_reader(file).unwrap(),
-            Err(err) => {
-                eprintln!("Error reading file: {}", err);
-                return;
-            }
-        };
-        init_funds(funds.clone()).await.unwrap();
-        // pay all funds
-        let mut uids = vec![];
-        for f in funds.clone() {
-            uids.push(f.uid);
-        }
-        pay_funds_api(uids).await;
-        // transfer the funds
-        // time::sleep(Duration::from_secs(10)).await;
-        // transfer_funds_to_one_account(funds).await;
-        // get_fund_account(vec![100001]).await;
+    async fn test_system_account_is_not_a_user() {
+        let uid = ledger::UPSTREAM_FUND_UID;
+        let body = json!([new_uid(), uid]).to_string();
+        let (status, body) = call(query_user_amount(headers(), body).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::BAD_REQUEST, &json!(1001))
+        );
+        let body = json!({"batchPayId": Uuid::new_v4().to_string(), "uids": [uid]}).to_string();
+        let (status, body) = call(batch_pay(headers(), body).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::BAD_REQUEST, &json!(1001))
+        );
     }
 
-    #[allow(dead_code)]
-    async fn get_fund_account(uids: Vec<i64>) {
-        let unique_id = Uuid::new_v4().to_string();
-        let json_data = json!(uids);
-        let response = Client::new()
-            .post("http://127.0.0.1:20004/onePass/queryUserAmount")
-            .header("Content-Type", "application/json")
-            .header("X-KSY-REQUEST-ID", unique_id)
-            .body(json_data.to_string())
-            .send()
-            .await
-            .unwrap();
-        let status = response.status();
-        let body = response.text().await.unwrap();
-        println!("Response status code: {}", status);
-        println!("Response body: {}", body);
+    #[tokio::test]
+    async fn test_idempotency_stats() {
+        let (status, body) = call(idempotency_stats(headers()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"]["batchPay"]["len"].is_u64());
+        assert!(body["data"]["trade"]["evictions"].is_u64());
     }
 
-    async fn pay_funds_api(uids: Vec<i64>) {
-        let unique_id = Uuid::new_v4().to_string();
-        let data = BatchPayJson {
-            batch_pay_id: unique_id.clone(),
-            uids,
-        };
-        let json_data = json!(data);
-        let _response = Client::new()
-            .post("http://127.0.0.1:20004/onePass/batchPay")
-            .header("Content-Type", "application/json")
-            .header("X-KSY-REQUEST-ID", unique_id)
-            .body(json_data.to_string())
-            .send()
-            .await
-            .unwrap();
-    }
+    #[tokio::test]
+    async fn test_account_lifecycle() {
+        let (uid, other) = (new_uid(), new_uid());
+        let account = |uid: i64| json!({ "uid": uid }).to_string();
+        let (status, body) = call(freeze_account(headers(), account(uid)).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::NOT_FOUND, &json!(2001))
+        );
 
-    async fn transfer_funds_to_one_account(funds: Vec<Fund>) {
-        let time_start = Instant::now();
-        // transfer the funds to one account
-        for f in funds {
-            if f.uid == 100001 {
-                continue;
-            }
-            transfer_api(f.uid, 100001, f.amount)
-                .await
-                .map_err(|err| format!("Error transfering fund: {}", err.to_string()))
-                .unwrap();
-        }
-        println!("Transfer time: {}ms", time_start.elapsed().as_millis());
-    }
+        let (status, body) = call(open_account(headers(), account(uid)).await).await;
+        assert_eq!(
+            (status, &body["data"]["status"]),
+            (StatusCode::OK, &json!("active"))
+        );
+        call(open_account(headers(), account(other)).await).await;
+        let (_, body) = call(freeze_account(headers(), account(uid)).await).await;
+        assert_eq!(body["data"]["status"], "frozen");
 
-    async fn transfer_api(from: i64, to: i64, amount: f64) -> anyhow::Result<()> {
-        let data = UserTradeJson {
-            source_uid: from,
-            target_uid: to,
-            amount,
-        };
-        let json_data = json!(data);
-        let unique_id = Uuid::new_v4().to_string();
-        let response = Client::new()
-            .post("http://127.0.0.1:20004/onePass/userTrade")
-            .header("Content-Type", "application/json")
-            .header("X-KSY-REQUEST-ID", unique_id)
-            .body(json_data.to_string())
-            .send()
-            .await
-            .unwrap();
-        let status = response.status();
-        let body = response.text().await.unwrap();
-        println!("Response status code: {status}");
-        println!("REsponse body: {body}");
-        Ok(())
+        let trade = json!({"sourceUid": uid, "targetUid": other, "amount": 1}).to_string();
+        let (status, body) = call(user_trade(RequiredKsyHeaders(headers()), trade).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::CONFLICT, &json!(2005))
+        );
+
+        let (_, body) = call(unfreeze_account(headers(), account(uid)).await).await;
+        assert_eq!(body["data"]["status"], "active");
+        let (_, body) = call(close_account(headers(), account(uid)).await).await;
+        assert_eq!(body["data"]["status"], "closed");
+        for response in [
+            call(freeze_account(headers(), account(uid)).await).await,
+            call(unfreeze_account(headers(), account(uid)).await).await,
+            call(open_account(headers(), account(uid)).await).await,
+            call(close_account(headers(), account(uid)).await).await,
+        ] {
+            assert_eq!(
+                (response.0, &response.1["code"]),
+                (StatusCode::CONFLICT, &json!(2006))
+            );
+        }
+        let (status, body) = call(close_account(headers(), "{}".to_string()).await).await;
+        assert_eq!(
+            (status, &body["code"]),
+            (StatusCode::BAD_REQUEST, &json!(1001))
+        );
     }
 }
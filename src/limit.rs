@@ -0,0 +1,239 @@
+use std::{
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    auth::{Permission, MAX_BODY},
+    config::RateLimit,
+    error::{ApiError, AppError},
+    extract::{CALLER_ID_HEADER, REQUEST_ID_HEADER},
+    GLOBAL_CONFIG,
+};
+
+// how long a batchPay turned away for the job cap is told to wait
+const BATCH_JOB_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// buckets are pruned every this many checks
+const PRUNE_EVERY: u64 = 4096;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// RateLimiter is a token bucket per key: a key may make `burst` requests
+// at once and `rate` per second after that. A rate of 0 lets everything through
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<K, Bucket>,
+    checks: AtomicU64,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(config: &RateLimit) -> Self {
+        RateLimiter {
+            rate: config.rate.max(0.0),
+            burst: config.burst.max(1.0),
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    // check takes a token of key, or returns how long until one is there
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        if self.checks.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1 {
+            self.prune(now);
+        }
+        let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    // prune drops the buckets that filled up again, they are the same as new ones
+    fn prune(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+fn semaphore(permits: usize) -> Semaphore {
+    match permits {
+        0 => Semaphore::new(Semaphore::MAX_PERMITS),
+        permits => Semaphore::new(permits),
+    }
+}
+
+static PER_CALLER: LazyLock<RateLimiter<String>> =
+    LazyLock::new(|| RateLimiter::new(&GLOBAL_CONFIG.limits.per_caller));
+
+static PER_SOURCE_UID: LazyLock<RateLimiter<i64>> =
+    LazyLock::new(|| RateLimiter::new(&GLOBAL_CONFIG.limits.per_source_uid));
+
+// BATCH_JOBS has a permit for every batch pay job that may run at once
+static BATCH_JOBS: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(semaphore(GLOBAL_CONFIG.limits.batch_jobs)));
+
+// UPSTREAM_IN_FLIGHT has a permit for every get_pay transaction that may
+// be in flight at once, across all jobs
+pub static UPSTREAM_IN_FLIGHT: LazyLock<Semaphore> =
+    LazyLock::new(|| semaphore(GLOBAL_CONFIG.limits.upstream_in_flight));
+
+// job_in_flight returns the get_pay slots of one batch pay job, shared by
+// all of its uids
+pub fn job_in_flight() -> Arc<Semaphore> {
+    Arc::new(semaphore(GLOBAL_CONFIG.limits.job_in_flight))
+}
+
+// admit_batch_job returns the permit of a new job, to be held until the
+// job ends, or how long to wait if as many jobs as allowed are running
+pub fn admit_batch_job() -> Result<OwnedSemaphorePermit, Duration> {
+    BATCH_JOBS
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| BATCH_JOB_RETRY_AFTER)
+}
+
+// wait_batch_job waits for the permit of a job that was accepted before
+// a restart, such a job can not be turned away
+pub async fn wait_batch_job() -> OwnedSemaphorePermit {
+    BATCH_JOBS
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("the batch job semaphore is never closed")
+}
+
+#[derive(Deserialize)]
+struct TradeSource {
+    #[serde(rename = "sourceUid")]
+    source_uid: i64,
+}
+
+// rate_limit is the middleware behind auth::authenticate, every request
+// takes a token of its caller and a userTrade one of its source uid too
+pub async fn rate_limit(
+    State(permission): State<Permission>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let caller_id = request
+        .headers()
+        .get(CALLER_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let limited = |retry_after: Duration, key: String| {
+        tracing::warn!("request {} rate limited by {}", request_id, key);
+        AppError::RateLimited(retry_after).with_request_id(&request_id)
+    };
+    PER_CALLER
+        .check(caller_id.clone())
+        .map_err(|retry_after| limited(retry_after, format!("caller {caller_id}")))?;
+    if permission != Permission::UserTrade {
+        return Ok(next.run(request).await);
+    }
+
+    // a body that does not parse is left to the handler to reject
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_BODY)
+        .await
+        .map_err(|err| AppError::InvalidBody(err.to_string()).with_request_id(&request_id))?;
+    if let Ok(TradeSource { source_uid }) = serde_json::from_slice(&bytes) {
+        PER_SOURCE_UID
+            .check(source_uid)
+            .map_err(|retry_after| limited(retry_after, format!("source uid {source_uid}")))?;
+    }
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(&RateLimit {
+            rate: 10.0,
+            burst: 3.0,
+        });
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(1, start).is_ok());
+        }
+        let retry_after = limiter.check_at(1, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(100));
+        // keys do not share tokens
+        assert!(limiter.check_at(2, start).is_ok());
+
+        // one token every 100ms, never more than burst
+        let later = start + Duration::from_millis(150);
+        assert!(limiter.check_at(1, later).is_ok());
+        let retry_after = limiter.check_at(1, later).unwrap_err();
+        assert_eq!(retry_after.as_millis(), 50);
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(1, much_later).is_ok());
+        }
+        assert!(limiter.check_at(1, much_later).is_err());
+
+        // 2 filled up again, 1 is still empty
+        limiter.prune(much_later);
+        assert_eq!(limiter.len(), 1);
+
+        let unlimited = RateLimiter::new(&RateLimit {
+            rate: 0.0,
+            burst: 0.0,
+        });
+        for _ in 0..1000 {
+            assert!(unlimited.check_at(1, start).is_ok());
+        }
+        assert_eq!(unlimited.len(), 0);
+    }
+}
@@ -1,13 +1,23 @@
-use std::{net::SocketAddr, str::FromStr, sync::LazyLock};
+use std::{net::SocketAddr, str::FromStr, sync::LazyLock, time::Duration};
 
 use axum::Router;
 use config::Config;
 use router::routers;
 
+mod auth;
+mod batch_job;
 mod config;
 mod db;
+mod drain;
+mod error;
+mod extract;
 mod fund;
 mod handler;
+mod limit;
+mod money;
+mod retry;
+mod sign;
+mod upstream;
 mod uuid_cache;
 mod router;
 
@@ -22,12 +32,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let config = &*GLOBAL_CONFIG;
+    // 启动前先恢复余额数据
+    LazyLock::force(&db::api::MY_ENGINE);
+    if let Err(err) = db::api::verify_ledger() {
+        tracing::error!("ledger check failed: {}", err);
+    }
+    LazyLock::force(&uuid_cache::UUID_CACHE_INSTANCE);
+    batch_job::resume_all(&upstream::PROVIDER);
+    if config.db.snapshot_interval > 0 {
+        tokio::spawn(db::api::run_snapshots(
+            Duration::from_secs(config.db.snapshot_interval),
+            Duration::from_secs(config.idempotency.ttl),
+        ));
+    }
 
     let app = Router::new().merge(routers());
 
     let addr = SocketAddr::from_str(&format!("{}:{}", config.server.addr, config.server.port))?;
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    tracing::info!("listening on {}", addr);
+    // port 0 picks a free port, the log line tells which one
+    tracing::info!("listening on {}", listener.local_addr()?);
     axum::serve(listener, app).await?;
 
     Ok(())
@@ -0,0 +1,167 @@
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Number, Value};
+
+// Money is an exact amount in cents, it is parsed from and written to
+// JSON decimals without ever going through f64
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneyError {
+    Invalid,
+    TooManyDecimals,
+    Negative,
+    Overflow,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            MoneyError::Invalid => "amount is not a valid decimal number",
+            MoneyError::TooManyDecimals => "amount has more than two fractional digits",
+            MoneyError::Negative => "amount can not be negative",
+            MoneyError::Overflow => "amount is too large",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl Money {
+    pub const fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    pub const fn cents(self) -> i64 {
+        self.0
+    }
+}
+
+fn all_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    // accepts plain and exponent notation, e.g. "12", "12.3", "1.23e2"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('-') {
+            return Err(MoneyError::Negative);
+        }
+        let (mantissa, exp) = match s.split_once(['e', 'E']) {
+            Some((mantissa, exp)) => {
+                let digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+                if !all_digits(digits) {
+                    return Err(MoneyError::Invalid);
+                }
+                // anything this large is out of range anyway
+                let exp = exp.parse::<i64>().map_err(|_| MoneyError::Overflow)?;
+                (mantissa, exp)
+            }
+            None => (s, 0),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if !all_digits(int) || (mantissa.contains('.') && !all_digits(frac)) {
+            return Err(MoneyError::Invalid);
+        }
+
+        // value = digits * 10^-scale
+        let mut digits = format!("{int}{frac}");
+        let mut scale = (frac.len() as i64).saturating_sub(exp);
+        while scale > 2 && digits.ends_with('0') {
+            digits.pop();
+            scale -= 1;
+        }
+        let digits = digits.trim_start_matches('0');
+        if digits.is_empty() {
+            return Ok(Money(0));
+        }
+        if scale > 2 {
+            return Err(MoneyError::TooManyDecimals);
+        }
+        let value = digits.parse::<i64>().map_err(|_| MoneyError::Overflow)?;
+        let shift = 2i64
+            .checked_sub(scale)
+            .and_then(|shift| u32::try_from(shift).ok())
+            .ok_or(MoneyError::Overflow)?;
+        10i64
+            .checked_pow(shift)
+            .and_then(|factor| value.checked_mul(factor))
+            .map(Money)
+            .ok_or(MoneyError::Overflow)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // always a valid JSON number, serde_json keeps the digits as they are
+        let number = Number::from_str(&self.to_string()).map_err(serde::ser::Error::custom)?;
+        number.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = match Value::deserialize(deserializer)? {
+            Value::Number(number) => number.to_string(),
+            Value::String(text) => text,
+            _ => return Err(de::Error::custom(MoneyError::Invalid)),
+        };
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cases = [
+            ("0", Ok(0)),
+            ("88.91", Ok(8891)),
+            ("10000.9", Ok(1000090)),
+            ("0.10", Ok(10)),
+            ("1.2300", Ok(123)),
+            ("1.23e2", Ok(12300)),
+            ("123E-2", Ok(123)),
+            ("0e300", Ok(0)),
+            ("0.001", Err(MoneyError::TooManyDecimals)),
+            ("1e-3", Err(MoneyError::TooManyDecimals)),
+            ("-1", Err(MoneyError::Negative)),
+            ("1e300", Err(MoneyError::Overflow)),
+            ("92233720368547758.08", Err(MoneyError::Overflow)),
+            ("NaN", Err(MoneyError::Invalid)),
+            ("1.", Err(MoneyError::Invalid)),
+            ("", Err(MoneyError::Invalid)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<Money>().map(Money::cents), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let amount: Money = serde_json::from_str("2345.35").unwrap();
+        assert_eq!(amount.cents(), 234535);
+        assert_eq!(json!(amount).to_string(), "2345.35");
+        assert_eq!(json!(Money::from_cents(100)).to_string(), "1.00");
+        assert_eq!(json!(Money::from_cents(-5)).to_string(), "-0.05");
+        assert!(serde_json::from_str::<Money>("0.001").is_err());
+        assert!(serde_json::from_str::<Money>("true").is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    future::Future,
+    sync::{LazyLock, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    config::{Breaker, Retry},
+    GLOBAL_CONFIG,
+};
+
+// RetryPolicy bounds how often and how long one upstream call is retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub deadline: Duration,
+}
+
+impl From<&Retry> for RetryPolicy {
+    fn from(config: &Retry) -> Self {
+        RetryPolicy {
+            max_attempts: config.max_attempts.max(1),
+            base_delay: Duration::from_millis(config.base_delay),
+            max_delay: Duration::from_millis(config.max_delay),
+            deadline: Duration::from_millis(config.deadline),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn budget(&self) -> Budget {
+        Budget {
+            policy: self.clone(),
+            started: Instant::now(),
+            attempts: 1,
+        }
+    }
+
+    // backoff is exponential in the number of failed attempts with full
+    // jitter, so callers that failed together do not retry together
+    fn backoff(&self, failed: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32.checked_shl(failed - 1).unwrap_or(u32::MAX))
+            .min(self.max_delay);
+        let jitter = (Uuid::new_v4().as_u128() as u64) % (exp.as_millis() as u64 + 1);
+        Duration::from_millis(jitter)
+    }
+}
+
+// Budget tracks the retries of one call, the first attempt is free
+pub struct Budget {
+    policy: RetryPolicy,
+    started: Instant,
+    attempts: u32,
+}
+
+impl Budget {
+    // retry returns how long to wait before the next attempt,
+    // or None if the attempts or the deadline are used up
+    pub fn retry(&mut self) -> Option<Duration> {
+        if self.attempts >= self.policy.max_attempts {
+            return None;
+        }
+        let delay = self.policy.backoff(self.attempts);
+        if self.started.elapsed() + delay > self.policy.deadline {
+            return None;
+        }
+        self.attempts += 1;
+        Some(delay)
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BreakerState {
+    Closed,
+    // calls are rejected without reaching the upstream
+    Open,
+    // the open period is over, a single probe goes through and the other
+    // calls are rejected until it reports
+    HalfOpen,
+}
+
+struct BreakerInner {
+    failures: u32,
+    open_until: Option<Instant>,
+    // a probe that never reports, because its caller went away, stops
+    // holding the breaker once this passes
+    probe_until: Option<Instant>,
+}
+
+// CircuitBreaker stops calling an upstream after `failure_threshold`
+// consecutive failures and tries again after `open_for`
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+impl From<&Breaker> for CircuitBreaker {
+    fn from(config: &Breaker) -> Self {
+        CircuitBreaker::new(
+            config.failure_threshold,
+            Duration::from_millis(config.open_for),
+        )
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            inner: Mutex::new(BreakerInner {
+                failures: 0,
+                open_until: None,
+                probe_until: None,
+            }),
+        }
+    }
+
+    fn state_at(inner: &BreakerInner, now: Instant) -> BreakerState {
+        match inner.open_until {
+            None => BreakerState::Closed,
+            Some(until) if now < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::state_at(&inner, Instant::now())
+    }
+
+    // allow lets every call through while closed, and only the first one
+    // once the open period is over, the caller must report how it went
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        match Self::state_at(&inner, now) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if inner.probe_until.is_some_and(|until| now < until) => false,
+            BreakerState::HalfOpen => {
+                inner.probe_until = Some(now + self.open_for);
+                true
+            }
+        }
+    }
+
+    pub fn success(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.failures = 0;
+        inner.open_until = None;
+        inner.probe_until = None;
+    }
+
+    pub fn failure(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.failures += 1;
+        inner.probe_until = None;
+        // a failure while half open opens the breaker again right away
+        if inner.failures >= self.failure_threshold || inner.open_until.is_some() {
+            inner.open_until = Some(Instant::now() + self.open_for);
+        }
+    }
+}
+
+// Breakers has one breaker per upstream endpoint
+pub struct Breakers {
+    pub get_pay: CircuitBreaker,
+    pub init_funds: CircuitBreaker,
+    pub batch_pay_finish: CircuitBreaker,
+}
+
+pub static BREAKERS: LazyLock<Breakers> = LazyLock::new(|| {
+    let config = &GLOBAL_CONFIG.upstream.breaker;
+    Breakers {
+        get_pay: config.into(),
+        init_funds: config.into(),
+        batch_pay_finish: config.into(),
+    }
+});
+
+pub fn policy() -> RetryPolicy {
+    (&GLOBAL_CONFIG.upstream.retry).into()
+}
+
+// with_retry calls `f` until it succeeds or the retry budget is used up,
+// attempts are skipped without calling `f` while the breaker is open
+pub async fn with_retry<T, F, Fut>(
+    name: &str,
+    policy: &RetryPolicy,
+    breaker: &CircuitBreaker,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut budget = policy.budget();
+    let mut last_error = anyhow!("circuit breaker is open");
+    loop {
+        if breaker.allow() {
+            match f().await {
+                Ok(value) => {
+                    breaker.success();
+                    return Ok(value);
+                }
+                Err(err) => {
+                    breaker.failure();
+                    last_error = err;
+                }
+            }
+        }
+        match budget.retry() {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => {
+                return Err(anyhow!(
+                    "{} gave up after {} attempts: {:#}",
+                    name,
+                    budget.attempts(),
+                    last_error
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, deadline: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+            deadline,
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = policy(100, Duration::from_secs(60));
+        for failed in 1..40 {
+            let cap = (10u64 << (failed - 1).min(2)).min(40);
+            assert!(policy.backoff(failed) <= Duration::from_millis(cap));
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut budget = policy(3, Duration::from_secs(60)).budget();
+        assert!(budget.retry().is_some());
+        assert!(budget.retry().is_some());
+        assert!(budget.retry().is_none());
+        assert_eq!(budget.attempts(), 3);
+
+        let mut budget = policy(100, Duration::ZERO).budget();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(budget.retry().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_with_retry() {
+        let breaker = CircuitBreaker::new(100, Duration::from_secs(60));
+        let mut calls = 0;
+        let result = with_retry(
+            "test",
+            &policy(5, Duration::from_secs(60)),
+            &breaker,
+            || {
+                calls += 1;
+                let ok = calls == 3;
+                async move {
+                    match ok {
+                        true => Ok(calls),
+                        false => Err(anyhow!("not yet")),
+                    }
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<()> = with_retry(
+            "test",
+            &policy(4, Duration::from_secs(60)),
+            &breaker,
+            || {
+                calls += 1;
+                async { Err(anyhow!("down")) }
+            },
+        )
+        .await;
+        assert_eq!(calls, 4);
+        assert!(result.unwrap_err().to_string().contains("down"));
+
+        // an open breaker keeps the upstream from being called at all
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.failure();
+        let mut calls = 0;
+        let result: Result<()> = with_retry(
+            "test",
+            &policy(3, Duration::from_secs(60)),
+            &breaker,
+            || {
+                calls += 1;
+                async { Ok(()) }
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.failure();
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // half open lets a single probe through until it reports
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_breaker_probe_that_never_reports() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        // the probe went away without a word, another one may try
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+}
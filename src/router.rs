@@ -1,13 +1,78 @@
-use axum::{routing::post, Router};
+use axum::{
+    middleware,
+    routing::{get, post, MethodRouter},
+    Router,
+};
 
-use crate::handler::{batch_pay, query_user_amount, user_trade};
+use crate::{
+    auth::{self, Permission},
+    handler::{
+        account_history, batch_pay, batch_pay_status, close_account, freeze_account,
+        idempotency_stats, open_account, query_user_amount, unfreeze_account, user_trade,
+    },
+    limit, GLOBAL_CONFIG,
+};
+
+// guard authenticates the caller of a route and then applies the rate
+// limits, both know the route by the permission it needs
+fn guard(route: MethodRouter, permission: Permission) -> MethodRouter {
+    route
+        .layer(middleware::from_fn_with_state(
+            permission,
+            limit::rate_limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            permission,
+            auth::authenticate,
+        ))
+}
+
+// lifecycle are the account admin routes, they are only mounted with auth
+// enabled, without it anyone who can reach the port could close accounts
+fn lifecycle() -> Router {
+    Router::new()
+        .route(
+            "/openAccount",
+            guard(post(open_account), Permission::OpenAccount),
+        )
+        .route(
+            "/freezeAccount",
+            guard(post(freeze_account), Permission::FreezeAccount),
+        )
+        .route(
+            "/unfreezeAccount",
+            guard(post(unfreeze_account), Permission::UnfreezeAccount),
+        )
+        .route(
+            "/closeAccount",
+            guard(post(close_account), Permission::CloseAccount),
+        )
+}
 
 pub fn routers() -> Router {
-    Router::new().nest(
-        "/onePass",
-        Router::new()
-            .route("/batchPay", post(batch_pay))
-            .route("/userTrade", post(user_trade))
-            .route("/queryUserAmount", post(query_user_amount)),
-    )
+    let mut routes = Router::new()
+        .route("/batchPay", guard(post(batch_pay), Permission::BatchPay))
+        .route("/userTrade", guard(post(user_trade), Permission::UserTrade))
+        .route(
+            "/queryUserAmount",
+            guard(post(query_user_amount), Permission::QueryUserAmount),
+        )
+        .route(
+            "/accountHistory",
+            guard(post(account_history), Permission::AccountHistory),
+        )
+        .route(
+            "/batchPayStatus",
+            guard(post(batch_pay_status), Permission::BatchPayStatus),
+        )
+        .route(
+            "/idempotencyStats",
+            guard(get(idempotency_stats), Permission::IdempotencyStats),
+        );
+    if GLOBAL_CONFIG.auth.enabled {
+        routes = routes.merge(lifecycle());
+    } else {
+        tracing::warn!("auth is disabled, the account lifecycle routes are not mounted");
+    }
+    Router::new().nest("/onePass", routes)
 }
@@ -0,0 +1,346 @@
+// HMAC-SHA256 request signing between balance-api and the fund service.
+//
+// A signed request carries three headers: the key id, a timestamp in unix
+// milliseconds and the hex HMAC-SHA256 under the secret of that key of
+//
+//   <method>\n<path>\n<caller id>\n<request id>\n<timestamp>\n<body>
+//
+// where the caller and request id are X-KSY-KINGSTAR-ID and X-KSY-REQUEST-ID,
+// empty when the request has none. The receiving side looks the secret up by key id,
+// rejects timestamps outside its window and compares in constant time.
+//
+// This file only depends on hmac, sha2 and the http types, so fake_upstream
+// and the end-to-end tests include it as well.
+use std::{
+    fmt::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const REQUEST_ID_HEADER: &str = "X-KSY-REQUEST-ID";
+pub const CALLER_ID_HEADER: &str = "X-KSY-KINGSTAR-ID";
+pub const KEY_ID_HEADER: &str = "X-KSY-KEY-ID";
+pub const TIMESTAMP_HEADER: &str = "X-KSY-TIMESTAMP";
+pub const SIGNATURE_HEADER: &str = "X-KSY-SIGNATURE";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Signed is the part of a request a signature covers besides the timestamp
+#[derive(Debug, Clone, Copy)]
+pub struct Signed<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub caller_id: &'a str,
+    pub request_id: &'a str,
+    pub body: &'a [u8],
+}
+
+fn mac(secret: &[u8], timestamp: u64, request: &Signed) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    let timestamp = timestamp.to_string();
+    for field in [
+        request.method,
+        request.path,
+        request.caller_id,
+        request.request_id,
+        &timestamp,
+    ] {
+        mac.update(field.as_bytes());
+        mac.update(b"\n");
+    }
+    mac.update(request.body);
+    mac
+}
+
+// signature is the hex HMAC-SHA256 of request at timestamp
+pub fn signature(secret: &[u8], timestamp: u64, request: &Signed) -> String {
+    let digest = mac(secret, timestamp, request).finalize().into_bytes();
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Signer signs outbound requests with one key
+#[derive(Clone)]
+pub struct Signer {
+    key_id: String,
+    secret: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(key_id: &str, secret: &str) -> Self {
+        Signer {
+            key_id: key_id.to_string(),
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    // headers returns the signing headers of request, sent now
+    pub fn headers(&self, request: &Signed) -> [(&'static str, String); 3] {
+        self.headers_at(request, now_millis())
+    }
+
+    pub fn headers_at(&self, request: &Signed, timestamp: u64) -> [(&'static str, String); 3] {
+        [
+            (KEY_ID_HEADER, self.key_id.clone()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (
+                SIGNATURE_HEADER,
+                signature(&self.secret, timestamp, request),
+            ),
+        ]
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    // one of the signing headers is missing or not valid
+    Missing(&'static str),
+    UnknownKey(String),
+    // the timestamp is outside the accepted window
+    Expired,
+    BadSignature,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Missing(header) => write!(f, "{header} is missing or not valid"),
+            VerifyError::UnknownKey(key_id) => write!(f, "unknown key id {key_id}"),
+            VerifyError::Expired => write!(f, "{TIMESTAMP_HEADER} is outside the accepted window"),
+            VerifyError::BadSignature => write!(f, "signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// verify checks the signing headers of a request against its method, path,
+// ids and body, the secret of a key id comes from `secret_of`. Returns the key id
+pub fn verify<'a>(
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    max_skew: Duration,
+    secret_of: impl FnOnce(&str) -> Option<&'a [u8]>,
+) -> Result<String, VerifyError> {
+    verify_at(
+        method,
+        path,
+        headers,
+        body,
+        max_skew,
+        secret_of,
+        now_millis(),
+    )
+}
+
+pub fn verify_at<'a>(
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    max_skew: Duration,
+    secret_of: impl FnOnce(&str) -> Option<&'a [u8]>,
+    now: u64,
+) -> Result<String, VerifyError> {
+    let header = |name: &'static str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(VerifyError::Missing(name))
+    };
+    let key_id = header(KEY_ID_HEADER)?;
+    let timestamp: u64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| VerifyError::Missing(TIMESTAMP_HEADER))?;
+    let signature = from_hex(header(SIGNATURE_HEADER)?).ok_or(VerifyError::BadSignature)?;
+
+    let Some(secret) = secret_of(key_id) else {
+        return Err(VerifyError::UnknownKey(key_id.to_string()));
+    };
+    if now.abs_diff(timestamp) > max_skew.as_millis() as u64 {
+        return Err(VerifyError::Expired);
+    }
+    let id = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+    };
+    let request = Signed {
+        method,
+        path,
+        caller_id: id(CALLER_ID_HEADER),
+        request_id: id(REQUEST_ID_HEADER),
+        body,
+    };
+    mac(secret, timestamp, &request)
+        .verify_slice(&signature)
+        .map_err(|_| VerifyError::BadSignature)?;
+    Ok(key_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn request(body: &[u8]) -> Signed<'_> {
+        Signed {
+            method: "POST",
+            path: "/getPay",
+            caller_id: "20004",
+            request_id: "r1",
+            body,
+        }
+    }
+
+    // signed are the headers of request as sent, ids and signature
+    fn signed(signer: &Signer, request: &Signed, timestamp: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CALLER_ID_HEADER,
+            HeaderValue::from_str(request.caller_id).unwrap(),
+        );
+        headers.insert(
+            REQUEST_ID_HEADER,
+            HeaderValue::from_str(request.request_id).unwrap(),
+        );
+        for (name, value) in signer.headers_at(request, timestamp) {
+            headers.insert(name, HeaderValue::from_str(&value).unwrap());
+        }
+        headers
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        let mut mac = HmacSha256::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!(
+            hex(&mac.finalize().into_bytes()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let mut mac = HmacSha256::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"POST\n/getPay\n20004\nr1\n1700000000000\nwhat do ya want for nothing?");
+        assert_eq!(
+            signature(
+                b"Jefe",
+                1_700_000_000_000,
+                &request(b"what do ya want for nothing?")
+            ),
+            hex(&mac.finalize().into_bytes())
+        );
+        assert_eq!(
+            from_hex(&signature(b"k", 1, &request(b"{}")))
+                .unwrap()
+                .len(),
+            32
+        );
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+
+    #[test]
+    fn test_verify() {
+        let signer = Signer::new("k1", "secret");
+        let body = br#"{"batchPayId":"b1"}"#;
+        let now = 1_700_000_000_000;
+        let skew = Duration::from_secs(300);
+        let keys = |key_id: &str| (key_id == "k1").then_some(&b"secret"[..]);
+        let check = |headers: &HeaderMap, method, path, body: &[u8], now| {
+            verify_at(method, path, headers, body, skew, keys, now)
+        };
+
+        let headers = signed(&signer, &request(body), now);
+        assert_eq!(
+            check(&headers, "POST", "/getPay", body, now),
+            Ok("k1".into())
+        );
+        // the receiving clock may be off in either direction
+        assert!(check(&headers, "POST", "/getPay", body, now + 300_000).is_ok());
+        assert!(check(&headers, "POST", "/getPay", body, now - 300_000).is_ok());
+        assert_eq!(
+            check(&headers, "POST", "/getPay", body, now + 300_001),
+            Err(VerifyError::Expired)
+        );
+
+        // a changed body, timestamp, method, path or id does not verify
+        let tampered = br#"{"batchPayId":"b2"}"#;
+        assert_eq!(
+            check(&headers, "POST", "/getPay", tampered, now),
+            Err(VerifyError::BadSignature)
+        );
+        assert_eq!(
+            check(&headers, "GET", "/getPay", body, now),
+            Err(VerifyError::BadSignature)
+        );
+        assert_eq!(
+            check(&headers, "POST", "/batchPayFinish", body, now),
+            Err(VerifyError::BadSignature)
+        );
+        for (name, value) in [
+            (TIMESTAMP_HEADER, (now + 1).to_string()),
+            (CALLER_ID_HEADER, "30001".to_string()),
+            (REQUEST_ID_HEADER, "r2".to_string()),
+        ] {
+            let mut moved = headers.clone();
+            moved.insert(name, HeaderValue::from_str(&value).unwrap());
+            assert_eq!(
+                check(&moved, "POST", "/getPay", body, now),
+                Err(VerifyError::BadSignature),
+                "{name}"
+            );
+        }
+        let mut dropped = headers.clone();
+        dropped.remove(REQUEST_ID_HEADER);
+        assert_eq!(
+            check(&dropped, "POST", "/getPay", body, now),
+            Err(VerifyError::BadSignature)
+        );
+
+        let wrong_secret = signed(&Signer::new("k1", "guess"), &request(body), now);
+        assert_eq!(
+            check(&wrong_secret, "POST", "/getPay", body, now),
+            Err(VerifyError::BadSignature)
+        );
+        let unknown = signed(&Signer::new("k2", "secret"), &request(body), now);
+        assert_eq!(
+            check(&unknown, "POST", "/getPay", body, now),
+            Err(VerifyError::UnknownKey("k2".into()))
+        );
+        for name in [KEY_ID_HEADER, TIMESTAMP_HEADER, SIGNATURE_HEADER] {
+            let mut missing = headers.clone();
+            missing.remove(name);
+            assert!(check(&missing, "POST", "/getPay", body, now).is_err());
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{sync::LazyLock, time::Duration};
+
+use reqwest::Client;
+
+use crate::{
+    config::{Http, HttpVersion},
+    GLOBAL_CONFIG,
+};
+
+// build_client builds the client every upstream call goes through, so
+// connections and TLS sessions are reused across get_pay, init_funds and
+// batch_pay_finish instead of being set up per request
+pub fn build_client(config: &Http) -> reqwest::Result<Client> {
+    let millis = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(millis(config.pool_idle_timeout))
+        .tcp_keepalive(millis(config.tcp_keepalive))
+        .tcp_nodelay(true);
+    if let Some(timeout) = millis(config.connect_timeout) {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = millis(config.read_timeout) {
+        builder = builder.read_timeout(timeout);
+    }
+    builder = match config.version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+    builder.build()
+}
+
+// 出现错误直接 panic!
+pub static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    build_client(&GLOBAL_CONFIG.upstream.http).expect("Failed to build the upstream client")
+});
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use axum::{extract::ConnectInfo, routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, task::JoinSet};
+
+    use super::*;
+
+    fn config(version: HttpVersion) -> Http {
+        Http {
+            pool_max_idle_per_host: 64,
+            pool_idle_timeout: 90_000,
+            tcp_keepalive: 60_000,
+            connect_timeout: 1_000,
+            read_timeout: 2_000,
+            version,
+        }
+    }
+
+    // Peers are the client addresses a mock saw, one per connection
+    type Peers = Arc<Mutex<HashSet<SocketAddr>>>;
+
+    // mock_get_pay answers every get_pay with 200, like the fund service does
+    async fn mock_get_pay() -> (String, Peers) {
+        let peers = Peers::default();
+        let seen = peers.clone();
+        let app = Router::new().route(
+            "/getPay",
+            post(
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    seen.lock().unwrap().insert(peer);
+                    Json(json!({"code": 200, "requestId": "", "msg": "ok", "data": ""}))
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        (format!("http://{addr}/getPay"), peers)
+    }
+
+    async fn get_pay(client: &Client, url: &str) {
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(json!({"transactionId": "t", "uid": 1, "amount": 1}).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        serde_json::from_str::<Value>(&response.text().await.unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_client_reuses_connections() {
+        for version in [HttpVersion::Auto, HttpVersion::Http1] {
+            let (url, peers) = mock_get_pay().await;
+            let client = build_client(&config(version)).unwrap();
+            for _ in 0..3 {
+                get_pay(&client, &url).await;
+            }
+            assert_eq!(peers.lock().unwrap().len(), 1);
+        }
+        // a client per call connects every time
+        let (url, peers) = mock_get_pay().await;
+        for _ in 0..3 {
+            get_pay(&Client::new(), &url).await;
+        }
+        assert_eq!(peers.lock().unwrap().len(), 3);
+    }
+
+    // cargo test bench_upstream_client -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_upstream_client() {
+        const REQUESTS: usize = 500;
+        const CONCURRENCY: usize = 32;
+        let (url, _) = mock_get_pay().await;
+
+        async fn run(url: &str, client: Option<Client>) -> f64 {
+            let start = Instant::now();
+            let mut set = JoinSet::new();
+            for worker in 0..CONCURRENCY {
+                let (url, client) = (url.to_string(), client.clone());
+                set.spawn(async move {
+                    for _ in (worker..REQUESTS).step_by(CONCURRENCY) {
+                        // the old code built a new client for every call
+                        let client = client.clone().unwrap_or_else(Client::new);
+                        get_pay(&client, &url).await;
+                    }
+                });
+            }
+            while let Some(joined) = set.join_next().await {
+                joined.unwrap();
+            }
+            REQUESTS as f64 / start.elapsed().as_secs_f64()
+        }
+
+        let per_call = run(&url, None).await;
+        let shared = run(&url, Some(CLIENT.clone())).await;
+        println!("client per call: {per_call:>10.0} req/s");
+        println!("shared client:   {shared:>10.0} req/s");
+        assert!(shared > per_call);
+    }
+}
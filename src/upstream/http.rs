@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::FundProvider;
+use crate::{
+    config::Auth,
+    drain::BoxFuture,
+    extract::{CALLER_ID_HEADER, REQUEST_ID_HEADER},
+    fund::Fund,
+    money::Money,
+    sign::{Signed, Signer},
+    GLOBAL_CONFIG,
+};
+
+#[derive(Serialize)]
+struct GetFundJson<'a> {
+    #[serde(rename = "transactionId")]
+    transaction_id: &'a str,
+    uid: i64,
+    amount: Money,
+}
+
+#[derive(Deserialize)]
+struct GetFundResponse {
+    code: i32,
+    #[serde(rename = "requestId")]
+    request_id: String,
+}
+
+#[derive(Serialize)]
+struct FinishJson<'a> {
+    #[serde(rename = "batchPayId")]
+    batch_pay_id: &'a str,
+}
+
+// HttpProvider talks to the fund service at `urls` of the config
+pub struct HttpProvider {
+    client: Client,
+    caller_id: String,
+    signer: Option<Signer>,
+}
+
+impl HttpProvider {
+    // new signs every call when auth has a secret
+    pub fn new(client: Client, auth: &Auth) -> Self {
+        HttpProvider {
+            client,
+            caller_id: auth.caller_id.clone(),
+            signer: (!auth.secret.is_empty()).then(|| Signer::new(&auth.key_id, &auth.secret)),
+        }
+    }
+
+    // post builds a JSON call to the fund service, signed over the path,
+    // the ids and body
+    fn post(&self, url: &str, request_id: &str, body: String) -> RequestBuilder {
+        let mut request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(REQUEST_ID_HEADER, request_id)
+            .header(CALLER_ID_HEADER, &self.caller_id);
+        if let Some(signer) = &self.signer {
+            let path = Url::parse(url).map(|url| url.path().to_string());
+            let signed = Signed {
+                method: "POST",
+                path: path.as_deref().unwrap_or(""),
+                caller_id: &self.caller_id,
+                request_id,
+                body: body.as_bytes(),
+            };
+            for (name, value) in signer.headers(&signed) {
+                request = request.header(name, value);
+            }
+        }
+        request.body(body)
+    }
+}
+
+impl FundProvider for HttpProvider {
+    fn get_pay<'a>(
+        &'a self,
+        uid: i64,
+        amount: i64,
+        transaction_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>> {
+        Box::pin(async move {
+            println!("GETPAY uid: {uid}, amount: {amount}, uniqueID: {transaction_id}");
+            let data = GetFundJson {
+                transaction_id,
+                uid,
+                amount: Money::from_cents(amount),
+            };
+            let uuid = Uuid::new_v4().to_string();
+            let response = self
+                .post(&GLOBAL_CONFIG.urls.get_pay, &uuid, json!(data).to_string())
+                .send()
+                .await?;
+            let status = response.status();
+            let body = response.text().await?;
+            if status != StatusCode::OK {
+                return Err(anyhow!("get_pay failed with status code: {}", status));
+            }
+
+            let result = serde_json::from_str::<GetFundResponse>(&body)?;
+            if result.request_id != uuid {
+                return Err(anyhow!(
+                    "get_pay answered request {} instead of {}",
+                    result.request_id,
+                    uuid
+                ));
+            }
+            Ok(result.code)
+        })
+    }
+
+    fn init_funds<'a>(&'a self, funds: &'a [Fund]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let response = self
+                .post(
+                    &GLOBAL_CONFIG.urls.init_funds,
+                    "1",
+                    json!(funds).to_string(),
+                )
+                .send()
+                .await?;
+
+            let status = response.status();
+            let body = response.text().await?;
+
+            // 打印响应体
+            println!("Response status code: {}", status);
+            println!("Response body: {}", body);
+            if !status.is_success() {
+                return Err(anyhow!("init_funds failed with status code: {}", status));
+            }
+            Ok(())
+        })
+    }
+
+    fn batch_pay_finish<'a>(
+        &'a self,
+        request_id: &'a str,
+        batch_pay_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>> {
+        Box::pin(async move {
+            let data = FinishJson { batch_pay_id };
+            let response = self
+                .post(
+                    &GLOBAL_CONFIG.urls.batch_pay_finish,
+                    request_id,
+                    json!(data).to_string(),
+                )
+                .send()
+                .await?;
+            Ok(response.status().as_u16() as i32)
+        })
+    }
+}
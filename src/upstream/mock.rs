@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::time;
+
+use super::{
+    sim::{self, Balances},
+    FundProvider,
+};
+use crate::{drain::BoxFuture, fund::Fund};
+
+// MockProvider is an in-process fund service, so the batch pay flow can
+// run without the real one. It answers like the fake_upstream binary, both
+// simulate the service with upstream::sim
+pub struct MockProvider {
+    balances: Balances,
+    finished: Mutex<Vec<(String, String)>>,
+    // every call waits a random time up to delay
+    delay: Duration,
+    // share of get_pay calls that fail as if they timed out, the
+    // transaction is not applied
+    timeout_rate: f64,
+    // share of get_pay calls that are applied but answered only after late_by
+    late_rate: f64,
+    late_by: Duration,
+    // the next finish_failures batch_pay_finish calls are answered with 500
+    finish_failures: AtomicU32,
+    requests: AtomicU64,
+    // get_pay calls running right now, and the most there ever were
+    in_flight: AtomicU64,
+    peak_in_flight: AtomicU64,
+}
+
+impl MockProvider {
+    pub fn new() -> Self {
+        MockProvider {
+            balances: Balances::default(),
+            finished: Mutex::new(Vec::new()),
+            delay: Duration::ZERO,
+            timeout_rate: 0.0,
+            late_rate: 0.0,
+            late_by: Duration::ZERO,
+            finish_failures: AtomicU32::new(0),
+            requests: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            peak_in_flight: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_balance(self, uid: i64, cents: i64) -> Self {
+        self.balances.set(uid, cents);
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_timeouts(mut self, rate: f64) -> Self {
+        self.timeout_rate = rate;
+        self
+    }
+
+    pub fn with_late_answers(mut self, rate: f64, late_by: Duration) -> Self {
+        self.late_rate = rate;
+        self.late_by = late_by;
+        self
+    }
+
+    pub fn with_finish_failures(self, failures: u32) -> Self {
+        self.finish_failures.store(failures, Ordering::Relaxed);
+        self
+    }
+
+    pub fn balance(&self, uid: i64) -> Option<i64> {
+        self.balances.get(uid)
+    }
+
+    // finished lists every acknowledged batch_pay_finish as (request_id, batch_pay_id)
+    pub fn finished(&self) -> Vec<(String, String)> {
+        self.finished.lock().unwrap().clone()
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_in_flight(&self) -> u64 {
+        self.peak_in_flight.load(Ordering::Relaxed)
+    }
+
+    async fn wait(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        sim::jitter(self.delay).await;
+    }
+}
+
+impl FundProvider for MockProvider {
+    fn get_pay<'a>(
+        &'a self,
+        uid: i64,
+        amount: i64,
+        transaction_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>> {
+        Box::pin(async move {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+            self.peak_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+            let result = async {
+                self.wait().await;
+                if sim::chance(self.timeout_rate) {
+                    return Err(anyhow!("get_pay {} timed out", transaction_id));
+                }
+                let code = self.balances.pay(uid, amount, transaction_id);
+                if sim::chance(self.late_rate) {
+                    time::sleep(self.late_by).await;
+                }
+                Ok(code)
+            }
+            .await;
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            result
+        })
+    }
+
+    fn init_funds<'a>(&'a self, funds: &'a [Fund]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.wait().await;
+            for fund in funds {
+                self.balances.set(fund.uid, fund.amount.cents());
+            }
+            Ok(())
+        })
+    }
+
+    fn batch_pay_finish<'a>(
+        &'a self,
+        request_id: &'a str,
+        batch_pay_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>> {
+        Box::pin(async move {
+            self.wait().await;
+            if sim::fail_next(&self.finish_failures) {
+                return Ok(500);
+            }
+            self.finished
+                .lock()
+                .unwrap()
+                .push((request_id.to_string(), batch_pay_id.to_string()));
+            Ok(200)
+        })
+    }
+}
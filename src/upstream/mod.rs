@@ -0,0 +1,45 @@
+use std::sync::{Arc, LazyLock};
+
+use anyhow::Result;
+
+use crate::{drain::BoxFuture, fund::Fund, GLOBAL_CONFIG};
+
+mod client;
+mod http;
+#[cfg(test)]
+pub mod mock;
+// sim is shared with the fake_upstream binary, each uses only part of it
+#[cfg(test)]
+#[allow(dead_code)]
+mod sim;
+
+pub use self::http::HttpProvider;
+
+// FundProvider is the fund service the balances are pulled from. Every
+// call is a single attempt, retries and timeouts are up to the caller
+pub trait FundProvider: Send + Sync {
+    // get_pay asks for `amount` cents of uid and returns the code of the
+    // answer: 200 paid, 501 insufficient or 404 unknown uid. Sending the same
+    // transaction_id again must not pay twice
+    fn get_pay<'a>(
+        &'a self,
+        uid: i64,
+        amount: i64,
+        transaction_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>>;
+    fn init_funds<'a>(&'a self, funds: &'a [Fund]) -> BoxFuture<'a, Result<()>>;
+    // batch_pay_finish returns the status of the callback, request_id is
+    // the same on every attempt for one batch pay
+    fn batch_pay_finish<'a>(
+        &'a self,
+        request_id: &'a str,
+        batch_pay_id: &'a str,
+    ) -> BoxFuture<'a, Result<i32>>;
+}
+
+pub static PROVIDER: LazyLock<Arc<dyn FundProvider>> = LazyLock::new(|| {
+    Arc::new(HttpProvider::new(
+        client::CLIENT.clone(),
+        &GLOBAL_CONFIG.upstream.auth,
+    ))
+});
@@ -0,0 +1,78 @@
+// sim is the simulated fund service behind both MockProvider and the
+// fake_upstream binary, so the two answer alike. The binary includes this
+// file by path, it may only use std, tokio and uuid
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time;
+use uuid::Uuid;
+
+// chance is true with probability rate, it only looks at the first 48
+// bits of a v4 uuid since version and variant bits are fixed
+pub fn chance(rate: f64) -> bool {
+    ((Uuid::new_v4().as_u128() >> 80) as f64) < rate * (1u64 << 48) as f64
+}
+
+// jitter waits a random time up to delay
+pub async fn jitter(delay: Duration) {
+    if !delay.is_zero() {
+        let micros = Uuid::new_v4().as_u128() as u64 % (delay.as_micros() as u64 + 1);
+        time::sleep(Duration::from_micros(micros)).await;
+    }
+}
+
+// fail_next counts down failures, it is true while some are left
+pub fn fail_next(failures: &AtomicU32) -> bool {
+    failures
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+// Balances are the funds of every uid. Like the real service it remembers
+// the answer to every transactionId and never pays the same one twice
+#[derive(Default)]
+pub struct Balances {
+    balances: Mutex<HashMap<i64, i64>>,
+    answers: Mutex<HashMap<String, i32>>,
+}
+
+impl Balances {
+    pub fn set(&self, uid: i64, cents: i64) {
+        self.balances.lock().unwrap().insert(uid, cents);
+    }
+
+    pub fn get(&self, uid: i64) -> Option<i64> {
+        self.balances.lock().unwrap().get(&uid).copied()
+    }
+
+    // all lists (uid, cents) in no particular order
+    pub fn all(&self) -> Vec<(i64, i64)> {
+        let balances = self.balances.lock().unwrap();
+        balances.iter().map(|(uid, cents)| (*uid, *cents)).collect()
+    }
+
+    // pay answers a getPay: 200 paid, 501 insufficient or 404 unknown uid
+    pub fn pay(&self, uid: i64, amount: i64, transaction_id: &str) -> i32 {
+        let mut answers = self.answers.lock().unwrap();
+        if let Some(code) = answers.get(transaction_id) {
+            return *code;
+        }
+        let mut balances = self.balances.lock().unwrap();
+        let code = match balances.get_mut(&uid) {
+            None => 404,
+            Some(balance) if *balance < amount => 501,
+            Some(balance) => {
+                *balance -= amount;
+                200
+            }
+        };
+        answers.insert(transaction_id.to_string(), code);
+        code
+    }
+}
@@ -1,33 +1,481 @@
-use std::sync::LazyLock;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use dashmap::DashSet;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    db::{
+        self,
+        idempotency::{IdempotencyRecord, KeyScope, StoredResponse},
+        journal,
+    },
+    GLOBAL_CONFIG,
+};
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        (status, Json(self.body)).into_response()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    // first time this key is seen, the caller must `complete` or `release` it
+    New,
+    Replay(StoredResponse),
+    // the first request with this key has not finished yet
+    InProgress,
+    // the key was used before with a different body
+    Conflict,
+}
+
+struct Entry {
+    fingerprint: u64,
+    outcome: Option<StoredResponse>,
+}
+
+struct Bucket {
+    start: Instant,
+    keys: HashMap<String, Entry>,
+}
+
+// fingerprint is 64 bit FNV-1a of the request body, stable across builds
+pub fn fingerprint(body: &[u8]) -> u64 {
+    body.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// IdempotencyStore remembers keys for at least `ttl`, keys are grouped in
+// time buckets so expiring them is dropping whole buckets from the front
+pub struct IdempotencyStore {
+    buckets: Mutex<VecDeque<Bucket>>,
+    ttl: Duration,
+    bucket_width: Duration,
+    capacity: usize,
+    hits: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub len: usize,
+    pub hits: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, buckets: u32, capacity: usize) -> Self {
+        IdempotencyStore {
+            buckets: Mutex::new(VecDeque::new()),
+            ttl,
+            bucket_width: ttl / buckets.max(1),
+            capacity,
+            hits: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    // try_add returns false if key was already seen within the retention
+    // window. It is for keys that must not be forgotten before their ttl: a
+    // full store does not evict, it returns how long until its oldest keys
+    // expire instead
+    pub fn try_add(&self, key: String) -> Result<bool, Duration> {
+        self.try_add_at(key, Instant::now())
+    }
+
+    fn try_add_at(&self, key: String, now: Instant) -> Result<bool, Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        self.expire(&mut buckets, now);
+        if buckets.iter().any(|bucket| bucket.keys.contains_key(&key)) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        if let Some(oldest) = buckets
+            .front()
+            .filter(|_| Self::len(&buckets) >= self.capacity)
+        {
+            return Err(
+                (oldest.start + self.bucket_width + self.ttl).saturating_duration_since(now)
+            );
+        }
+        self.insert(&mut buckets, key, 0, now);
+        Ok(true)
+    }
+
+    // claim registers key for a request with the given body fingerprint,
+    // or tells how an earlier request with the same key went
+    pub fn claim(&self, key: String, fingerprint: u64) -> Claim {
+        self.claim_at(key, fingerprint, Instant::now())
+    }
+
+    fn claim_at(&self, key: String, fingerprint: u64, now: Instant) -> Claim {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        self.expire(&mut buckets, now);
+        if let Some(entry) = buckets.iter().find_map(|bucket| bucket.keys.get(&key)) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return match &entry.outcome {
+                _ if entry.fingerprint != fingerprint => Claim::Conflict,
+                Some(response) => Claim::Replay(response.clone()),
+                None => Claim::InProgress,
+            };
+        }
+
+        // over capacity, give up the oldest keys early
+        while Self::len(&buckets) >= self.capacity {
+            let Some(oldest) = buckets.pop_front() else {
+                break;
+            };
+            tracing::warn!(
+                "idempotency store is full, evicting {} keys before their ttl",
+                oldest.keys.len()
+            );
+            self.evictions
+                .fetch_add(oldest.keys.len() as u64, Ordering::Relaxed);
+        }
+
+        self.insert(&mut buckets, key, fingerprint, now);
+        Claim::New
+    }
+
+    fn len(buckets: &VecDeque<Bucket>) -> usize {
+        buckets.iter().map(|b| b.keys.len()).sum()
+    }
+
+    fn insert(&self, buckets: &mut VecDeque<Bucket>, key: String, fingerprint: u64, now: Instant) {
+        let entry = Entry {
+            fingerprint,
+            outcome: None,
+        };
+        match buckets.back_mut() {
+            Some(bucket) if now < bucket.start + self.bucket_width => {
+                bucket.keys.insert(key, entry);
+            }
+            _ => buckets.push_back(Bucket {
+                start: now,
+                keys: HashMap::from([(key, entry)]),
+            }),
+        }
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // complete stores the outcome of a claimed key for later replays
+    pub fn complete(&self, key: &str, response: StoredResponse) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = buckets.iter_mut().find_map(|b| b.keys.get_mut(key)) {
+            entry.outcome = Some(response);
+        }
+    }
+
+    // release forgets a claimed key, so the request can be retried
+    pub fn release(&self, key: &str) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        for bucket in buckets.iter_mut() {
+            bucket.keys.remove(key);
+        }
+    }
+
+    // expire drops buckets whose newest possible key is older than ttl
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while let Some(oldest) = buckets.front() {
+            if now < oldest.start + self.bucket_width + self.ttl {
+                break;
+            }
+            let evicted = buckets.pop_front().map(|b| b.keys.len()).unwrap_or(0);
+            self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+    }
+
+    // restore re-registers a key loaded from the engine, records must come oldest first
+    fn restore(&self, record: IdempotencyRecord, now: Instant, now_millis: u64) {
+        let age = Duration::from_millis(now_millis.saturating_sub(record.timestamp));
+        let Some(at) = now.checked_sub(age) else {
+            return;
+        };
+        if self.claim_at(record.key.clone(), record.fingerprint, at) != Claim::New {
+            return;
+        }
+        if let Some(outcome) = record.outcome {
+            self.complete(&record.key, outcome);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        CacheStats {
+            len: Self::len(&buckets),
+            hits: self.hits.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
 
 pub struct UuidCache {
-    batch_pay: DashSet<String>,
-    trade: DashSet<String>,
+    batch_pay: IdempotencyStore,
+    trade: IdempotencyStore,
 }
 
-pub static UUID_CACHE_INSTANCE: LazyLock<UuidCache> = LazyLock::new(|| UuidCache {
-    batch_pay: DashSet::new(),
-    trade: DashSet::new(),
-});
+impl UuidCache {
+    fn store(&self, scope: KeyScope) -> &IdempotencyStore {
+        match scope {
+            KeyScope::BatchPay => &self.batch_pay,
+            KeyScope::Trade => &self.trade,
+        }
+    }
 
-pub fn check_and_add_batch_pay(uuid: String) -> bool {
-    match UUID_CACHE_INSTANCE.batch_pay.contains(&uuid) {
-        true => false,
-        false => {
-            UUID_CACHE_INSTANCE.batch_pay.insert(uuid);
-            true
+    // claim asks the engine about a key the store does not know. A full
+    // store gives up keys before their ttl, the engine keeps every key that
+    // was acted on until it expires, so an evicted key is not taken twice
+    fn claim(&self, scope: KeyScope, key: &str, fingerprint: u64) -> Claim {
+        let store = self.store(scope);
+        let claim = store.claim(key.to_string(), fingerprint);
+        if claim != Claim::New {
+            return claim;
+        }
+        let Some(record) = db::api::idempotency_key(scope, key) else {
+            return claim;
+        };
+        let expires = record.timestamp + store.ttl.as_millis() as u64;
+        if expires <= journal::now_millis() {
+            return claim;
+        }
+        // the engine answers for this key until it expires
+        store.release(key);
+        match record.outcome {
+            _ if record.fingerprint != fingerprint => Claim::Conflict,
+            Some(response) => Claim::Replay(response),
+            None => Claim::InProgress,
         }
     }
 }
 
-pub fn check_and_add_trade(uuid: String) -> bool {
-    match UUID_CACHE_INSTANCE.trade.contains(&uuid) {
-        true => false,
-        false => {
-            UUID_CACHE_INSTANCE.trade.insert(uuid);
-            true
+pub static UUID_CACHE_INSTANCE: LazyLock<UuidCache> = LazyLock::new(|| {
+    let config = &GLOBAL_CONFIG.idempotency;
+    let store = || {
+        IdempotencyStore::new(
+            Duration::from_secs(config.ttl),
+            config.buckets,
+            config.capacity,
+        )
+    };
+    let cache = UuidCache {
+        batch_pay: store(),
+        trade: store(),
+    };
+
+    // keys persisted by the engine survive restarts
+    let (now, now_millis) = (Instant::now(), journal::now_millis());
+    let since = now_millis.saturating_sub(config.ttl * 1000);
+    let mut records = db::api::idempotency_keys(since);
+    records.sort_by_key(|record| record.timestamp);
+    tracing::info!("restoring {} idempotency keys", records.len());
+    for record in records {
+        match record.scope {
+            KeyScope::BatchPay => cache.batch_pay.restore(record, now, now_millis),
+            KeyScope::Trade => cache.trade.restore(record, now, now_millis),
         }
     }
+    cache
+});
+
+pub fn check_and_add_batch_pay(uuid: String) -> bool {
+    UUID_CACHE_INSTANCE.claim(KeyScope::BatchPay, &uuid, 0) == Claim::New
+}
+
+pub fn release_batch_pay(uuid: &str) {
+    UUID_CACHE_INSTANCE.batch_pay.release(uuid)
+}
+
+pub fn claim_trade(uuid: &str, fingerprint: u64) -> Claim {
+    UUID_CACHE_INSTANCE.claim(KeyScope::Trade, uuid, fingerprint)
+}
+
+pub fn complete_trade(uuid: &str, response: StoredResponse) {
+    UUID_CACHE_INSTANCE.trade.complete(uuid, response)
+}
+
+pub fn release_trade(uuid: &str) {
+    UUID_CACHE_INSTANCE.trade.release(uuid)
+}
+
+pub fn batch_pay_stats() -> CacheStats {
+    UUID_CACHE_INSTANCE.batch_pay.stats()
+}
+
+pub fn trade_stats() -> CacheStats {
+    UUID_CACHE_INSTANCE.trade.stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_within_ttl() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 6, 100);
+        let start = Instant::now();
+        assert_eq!(Claim::New, store.claim_at("a".into(), 0, start));
+        assert_ne!(
+            Claim::New,
+            store.claim_at("a".into(), 0, start + Duration::from_secs(59))
+        );
+        assert_eq!(
+            Claim::New,
+            store.claim_at("b".into(), 0, start + Duration::from_secs(30))
+        );
+
+        // "a" is expired, "b" is still inside its window
+        let later = start + Duration::from_secs(75);
+        assert_eq!(Claim::New, store.claim_at("a".into(), 0, later));
+        assert_ne!(Claim::New, store.claim_at("b".into(), 0, later));
+
+        let stats = store.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.inserts, 3);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[test]
+    fn test_replay_and_conflict() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 6, 100);
+        let body = fingerprint(b"{\"amount\": 1}");
+        assert_eq!(store.claim("a".into(), body), Claim::New);
+        assert_eq!(store.claim("a".into(), body), Claim::InProgress);
+
+        let response = StoredResponse {
+            status: 400,
+            body: serde_json::json!({"code": 2002}),
+        };
+        store.complete("a", response.clone());
+        assert_eq!(store.claim("a".into(), body), Claim::Replay(response));
+        assert_eq!(
+            store.claim("a".into(), fingerprint(b"{\"amount\": 2}")),
+            Claim::Conflict
+        );
+
+        assert_eq!(store.claim("b".into(), body), Claim::New);
+        store.release("b");
+        assert_eq!(store.claim("b".into(), body), Claim::New);
+    }
+
+    #[test]
+    fn test_restore_keeps_age() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 6, 100);
+        let (now, now_millis) = (Instant::now(), 1_000_000);
+        let record = |key: &str, age_secs: u64| IdempotencyRecord {
+            scope: KeyScope::Trade,
+            key: key.to_string(),
+            fingerprint: 7,
+            timestamp: now_millis - age_secs * 1000,
+            outcome: Some(StoredResponse {
+                status: 200,
+                body: serde_json::json!({"code": 200}),
+            }),
+        };
+        store.restore(record("old", 50), now, now_millis);
+        store.restore(record("new", 10), now, now_millis);
+
+        assert!(matches!(store.claim("new".into(), 7), Claim::Replay(_)));
+        assert_eq!(store.claim("new".into(), 8), Claim::Conflict);
+        // "old" was first seen 50s ago, so it expires 10s from now
+        let later = now + Duration::from_secs(25);
+        assert_eq!(store.claim_at("old".into(), 7, later), Claim::New);
+    }
+
+    #[tokio::test]
+    async fn test_evicted_key_is_not_taken_twice() {
+        let store = || IdempotencyStore::new(Duration::from_secs(60), 6, 1);
+        let cache = UuidCache {
+            batch_pay: store(),
+            trade: store(),
+        };
+        let (trade, batch_pay) = (
+            uuid::Uuid::new_v4().to_string(),
+            uuid::Uuid::new_v4().to_string(),
+        );
+        let response = StoredResponse {
+            status: 200,
+            body: serde_json::json!({"code": 200}),
+        };
+        let record = |scope, key: &str, outcome| IdempotencyRecord {
+            scope,
+            key: key.to_string(),
+            fingerprint: 0,
+            timestamp: journal::now_millis(),
+            outcome,
+        };
+        assert_eq!(cache.claim(KeyScope::Trade, &trade, 0), Claim::New);
+        db::api::remember(record(KeyScope::Trade, &trade, Some(response.clone())))
+            .await
+            .unwrap();
+        assert_eq!(cache.claim(KeyScope::BatchPay, &batch_pay, 0), Claim::New);
+        db::api::remember(record(KeyScope::BatchPay, &batch_pay, None))
+            .await
+            .unwrap();
+
+        // both stores hold a single key, the first ones are pushed out
+        assert_eq!(cache.claim(KeyScope::Trade, "other", 0), Claim::New);
+        assert_eq!(cache.claim(KeyScope::BatchPay, "other", 0), Claim::New);
+        assert!(cache.trade.stats().evictions > 0);
+        assert_eq!(
+            cache.claim(KeyScope::Trade, &trade, 0),
+            Claim::Replay(response)
+        );
+        assert_eq!(cache.claim(KeyScope::Trade, &trade, 1), Claim::Conflict);
+        assert_eq!(
+            cache.claim(KeyScope::BatchPay, &batch_pay, 0),
+            Claim::InProgress
+        );
+    }
+
+    #[test]
+    fn test_capacity_bound() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 6, 4);
+        let start = Instant::now();
+        for i in 0..10u64 {
+            let now = start + Duration::from_secs(i * 10 / 4);
+            assert_eq!(Claim::New, store.claim_at(i.to_string(), 0, now));
+            assert!(store.stats().len <= 4);
+        }
+        assert!(store.stats().evictions >= 6);
+        assert_ne!(
+            Claim::New,
+            store.claim_at("9".into(), 0, start + Duration::from_secs(25))
+        );
+    }
+
+    #[test]
+    fn test_try_add_does_not_evict() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 6, 2);
+        let start = Instant::now();
+        assert_eq!(store.try_add_at("a".into(), start), Ok(true));
+        assert_eq!(store.try_add_at("a".into(), start), Ok(false));
+        let later = start + Duration::from_secs(30);
+        assert_eq!(store.try_add_at("b".into(), later), Ok(true));
+        // full, "a" is kept until its bucket expires 70s after start
+        assert_eq!(
+            store.try_add_at("c".into(), later),
+            Err(Duration::from_secs(40))
+        );
+        assert_eq!(store.try_add_at("a".into(), later), Ok(false));
+        assert_eq!(store.stats().evictions, 0);
+        let expired = start + Duration::from_secs(70);
+        assert_eq!(store.try_add_at("c".into(), expired), Ok(true));
+    }
 }
@@ -0,0 +1,332 @@
+// Harness runs balance-api against fake_upstream, both on ephemeral ports
+// and with a throwaway data directory, and talks to them over HTTP
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, Response, StatusCode};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+#[path = "../../src/sign.rs"]
+#[allow(dead_code)]
+pub mod sign;
+
+use sign::{Signed, Signer};
+
+// the key both servers share, the fake rejects calls not signed with it
+pub const KEY_ID: &str = "e2e";
+// CALLER_ID signs its calls to balance-api and may call every endpoint,
+// READER_ID only has an api key and may only query balances
+pub const CALLER_ID: &str = "20004";
+pub const READER_ID: &str = "30001";
+pub const READER_KEY: &str = "reader-key";
+
+// Process kills the child when dropped
+pub struct Process {
+    child: Child,
+    pub addr: String,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Process {
+    // spawn waits for the "listening on <addr>" line, the rest of the
+    // output is drained so the child never blocks on a full pipe, and
+    // echoed when E2E_VERBOSE is set
+    fn spawn(mut command: Command) -> Process {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("failed to start process");
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let verbose = env::var_os("E2E_VERBOSE").is_some();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if verbose {
+                    eprintln!("{line}");
+                }
+                if let Some((_, rest)) = line.split_once("listening on ") {
+                    let addr: String = rest
+                        .chars()
+                        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == ':')
+                        .collect();
+                    let _ = tx.send(addr);
+                }
+            }
+        });
+        let addr = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("process did not start listening");
+        Process { child, addr }
+    }
+}
+
+pub struct Harness {
+    pub upstream: Process,
+    pub api: Process,
+    dir: PathBuf,
+    client: Client,
+    // signs calls to the fake
+    pub signer: Signer,
+    // signs calls of CALLER_ID to balance-api
+    pub caller: Signer,
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Harness {
+    // start runs both servers, faults are FAKE_UPSTREAM_* variables for the fake
+    pub fn start(faults: &[(&str, &str)]) -> Harness {
+        Harness::start_with(faults, |_| {})
+    }
+
+    // start_with lets configure change the config of balance-api last
+    pub fn start_with(
+        faults: &[(&str, &str)],
+        configure: impl FnOnce(&mut serde_yaml::Value),
+    ) -> Harness {
+        let (secret, caller_secret) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let mut command = Command::new(env!("CARGO_BIN_EXE_fake_upstream"));
+        command
+            .env("FAKE_UPSTREAM_ADDR", "127.0.0.1:0")
+            .env("FAKE_UPSTREAM_KEYS", format!("{KEY_ID}:{secret}"));
+        for (name, value) in faults {
+            command.env(name, value);
+        }
+        let upstream = Process::spawn(command);
+
+        let dir = env::temp_dir().join(format!("balance-api-e2e-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let template = concat!(env!("CARGO_MANIFEST_DIR"), "/config.yaml");
+        let mut config: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string(template).unwrap()).unwrap();
+        let upstream_url = |path: &str| format!("http://{}/{}", upstream.addr, path).into();
+        config["server"]["port"] = 0.into();
+        config["urls"]["get_pay"] = upstream_url("getPay");
+        config["urls"]["init_funds"] = upstream_url("initFunds");
+        config["urls"]["batch_pay_finish"] = upstream_url("batchPayFinish");
+        config["upstream"]["auth"]["key_id"] = KEY_ID.into();
+        config["upstream"]["auth"]["secret"] = secret.clone().into();
+        config["auth"]["enabled"] = true.into();
+        config["auth"]["callers"] = serde_yaml::from_str(&format!(
+            r#"
+            "{CALLER_ID}":
+              keys: {{{KEY_ID}: "{caller_secret}"}}
+              permissions: [batchPay, batchPayStatus, userTrade, queryUserAmount, accountHistory,
+                openAccount, freezeAccount, unfreezeAccount, closeAccount]
+            "{READER_ID}":
+              api_keys: ["{READER_KEY}"]
+              permissions: [queryUserAmount]
+            "#
+        ))
+        .unwrap();
+        config["db"]["data_dir"] = dir.join("data").to_string_lossy().into_owned().into();
+        configure(&mut config);
+        let config_path = dir.join("config.yaml");
+        fs::write(&config_path, serde_yaml::to_string(&config).unwrap()).unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_balance-api"));
+        command
+            .env("BALANCE_API_CONFIG", &config_path)
+            .env("NO_COLOR", "1")
+            .current_dir(&dir);
+        let api = Process::spawn(command);
+
+        Harness {
+            upstream,
+            api,
+            dir,
+            client: Client::new(),
+            signer: Signer::new(KEY_ID, &secret),
+            caller: Signer::new(KEY_ID, &caller_secret),
+        }
+    }
+
+    pub async fn post(&self, path: &str, body: &Value) -> (StatusCode, Value) {
+        self.post_with_id(path, &Uuid::new_v4().to_string(), body)
+            .await
+    }
+
+    // post_with_id calls balance-api as CALLER_ID
+    pub async fn post_with_id(
+        &self,
+        path: &str,
+        request_id: &str,
+        body: &Value,
+    ) -> (StatusCode, Value) {
+        let body = body.to_string();
+        let headers = self.signed_headers(path, request_id, &body);
+        self.post_with_headers(path, &headers, body).await
+    }
+
+    // signed_headers are the X-KSY-* headers of CALLER_ID sending body to path
+    pub fn signed_headers(
+        &self,
+        path: &str,
+        request_id: &str,
+        body: &str,
+    ) -> Vec<(&'static str, String)> {
+        sign_as(&self.caller, path, CALLER_ID, request_id, body)
+    }
+
+    // send calls balance-api with exactly these X-KSY-* headers
+    pub async fn send(&self, path: &str, headers: &[(&str, String)], body: String) -> Response {
+        let mut request = self
+            .client
+            .post(format!("http://{}/onePass/{}", self.api.addr, path))
+            .header("Content-Type", "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        request.body(body).send().await.unwrap()
+    }
+
+    pub async fn post_with_headers(
+        &self,
+        path: &str,
+        headers: &[(&str, String)],
+        body: String,
+    ) -> (StatusCode, Value) {
+        let response = self.send(path, headers, body).await;
+        let status = response.status();
+        let body = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        (status, body)
+    }
+
+    pub async fn init_funds(&self, funds: &Value) {
+        let status = self
+            .post_upstream("initFunds", funds, Some(&self.signer))
+            .await;
+        assert!(status.is_success());
+    }
+
+    // post_upstream calls the fake directly, signed by signer if any
+    pub async fn post_upstream(
+        &self,
+        path: &str,
+        body: &Value,
+        signer: Option<&Signer>,
+    ) -> StatusCode {
+        let body = body.to_string();
+        let request_id = Uuid::new_v4().to_string();
+        let mut request = self
+            .client
+            .post(format!("http://{}/{}", self.upstream.addr, path))
+            .header("Content-Type", "application/json")
+            .header("X-KSY-REQUEST-ID", &request_id);
+        if let Some(signer) = signer {
+            let signed = Signed {
+                method: "POST",
+                path: &format!("/{path}"),
+                caller_id: "",
+                request_id: &request_id,
+                body: body.as_bytes(),
+            };
+            for (name, value) in signer.headers(&signed) {
+                request = request.header(name, value);
+            }
+        }
+        request.body(body).send().await.unwrap().status()
+    }
+
+    pub async fn upstream_state(&self) -> Value {
+        let response = self
+            .client
+            .get(format!("http://{}/state", self.upstream.addr))
+            .send()
+            .await
+            .unwrap();
+        serde_json::from_str(&response.text().await.unwrap()).unwrap()
+    }
+
+    pub async fn batch_pay(&self, batch_pay_id: &str, uids: &[i64]) -> (StatusCode, Value) {
+        let body = json!({"batchPayId": batch_pay_id, "uids": uids});
+        self.post("batchPay", &body).await
+    }
+
+    // wait_batch_pay polls batchPayStatus until the job finished or failed
+    pub async fn wait_batch_pay(&self, batch_pay_id: &str) -> Value {
+        let start = Instant::now();
+        loop {
+            let (_, body) = self
+                .post("batchPayStatus", &json!({"batchPayId": batch_pay_id}))
+                .await;
+            let state = &body["data"]["state"];
+            if state == "finished" || state == "failed" {
+                return body["data"].clone();
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "batch pay {batch_pay_id} did not finish: {body}"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    // balances returns the balance of every uid in cents
+    pub async fn balances(&self, uids: &[i64]) -> Vec<i64> {
+        let (status, body) = self.post("queryUserAmount", &json!(uids)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|fund| cents(&fund["amount"]))
+            .collect()
+    }
+}
+
+// cents reads an amount the way the services write it, a decimal with at
+// most two places
+pub fn cents(amount: &Value) -> i64 {
+    let text = match amount {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    let (units, fraction) = text.split_once('.').unwrap_or((&text, ""));
+    let fraction = format!("{fraction:0<2}");
+    units.parse::<i64>().unwrap() * 100 + fraction.parse::<i64>().unwrap()
+}
+
+// sign_as are the X-KSY-* headers of caller_id sending body to the
+// balance-api endpoint path, signed by signer
+pub fn sign_as(
+    signer: &Signer,
+    path: &str,
+    caller_id: &str,
+    request_id: &str,
+    body: &str,
+) -> Vec<(&'static str, String)> {
+    let path = format!("/onePass/{path}");
+    let signed = Signed {
+        method: "POST",
+        path: &path,
+        caller_id,
+        request_id,
+        body: body.as_bytes(),
+    };
+    let mut headers = vec![
+        ("X-KSY-REQUEST-ID", request_id.to_string()),
+        ("X-KSY-KINGSTAR-ID", caller_id.to_string()),
+    ];
+    headers.extend(signer.headers(&signed));
+    headers
+}
@@ -0,0 +1,393 @@
+mod common;
+
+use std::fs;
+
+use common::{cents, sign::Signer, sign_as, Harness, CALLER_ID, KEY_ID, READER_ID, READER_KEY};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+fn fixture(name: &str) -> Value {
+    let path = format!("{}/testfile/{}", env!("CARGO_MANIFEST_DIR"), name);
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+fn uids(funds: &Value) -> Vec<i64> {
+    funds
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|fund| fund["uid"].as_i64().unwrap())
+        .collect()
+}
+
+fn amounts(funds: &Value) -> Vec<i64> {
+    funds
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|fund| cents(&fund["amount"]))
+        .collect()
+}
+
+// assert_drained checks that every uid got exactly its upstream balance,
+// the upstream is empty and batch_pay_finish was delivered once
+async fn assert_drained(harness: &Harness, batch_pay_id: &str, funds: &Value) {
+    let status = harness.wait_batch_pay(batch_pay_id).await;
+    assert_eq!(status["state"], "finished", "{status}");
+    assert_eq!(status["failed"], json!([]));
+    assert_eq!(harness.balances(&uids(funds)).await, amounts(funds));
+
+    let upstream = harness.upstream_state().await;
+    for fund in upstream["balances"].as_array().unwrap() {
+        assert_eq!(cents(&fund["amount"]), 0, "{fund}");
+    }
+    let finished = upstream["finished"].as_array().unwrap();
+    assert_eq!(finished.len(), 1, "{upstream}");
+    assert_eq!(finished[0]["batchPayId"], batch_pay_id);
+    // every call balance-api made was signed
+    assert_eq!(upstream["unauthorized"], 0);
+}
+
+#[tokio::test]
+async fn test_batch_pay_from_file() {
+    let harness = Harness::start(&[]);
+    let funds = fixture("initFund100.json");
+    harness.init_funds(&funds).await;
+
+    let batch_pay_id = Uuid::new_v4().to_string();
+    let (status, body) = harness.batch_pay(&batch_pay_id, &uids(&funds)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_drained(&harness, &batch_pay_id, &funds).await;
+
+    // the same batchPayId is never paid twice
+    let (status, body) = harness.batch_pay(&batch_pay_id, &uids(&funds)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], 1003);
+}
+
+#[tokio::test]
+async fn test_batch_pay_big_funds() {
+    let harness = Harness::start(&[]);
+    let funds = fixture("initBigFund100.json");
+    harness.init_funds(&funds).await;
+
+    let batch_pay_id = Uuid::new_v4().to_string();
+    harness.batch_pay(&batch_pay_id, &uids(&funds)).await;
+    assert_drained(&harness, &batch_pay_id, &funds).await;
+}
+
+#[tokio::test]
+async fn test_batch_pay_with_upstream_faults() {
+    let harness = Harness::start(&[
+        ("FAKE_UPSTREAM_DELAY_MS", "5"),
+        ("FAKE_UPSTREAM_ERROR_RATE", "0.2"),
+        ("FAKE_UPSTREAM_TIMEOUT_RATE", "0.02"),
+        ("FAKE_UPSTREAM_HANG_MS", "1000"),
+        ("FAKE_UPSTREAM_FINISH_FAILURES", "2"),
+    ]);
+    let funds = json!([
+        {"uid": 100001, "amount": 88.91},
+        {"uid": 100042, "amount": 10000.93},
+        {"uid": 403131, "amount": 2345.35},
+    ]);
+    harness.init_funds(&funds).await;
+
+    let batch_pay_id = Uuid::new_v4().to_string();
+    // 100099 is unknown upstream and ends up with an empty account
+    let mut all = uids(&funds);
+    all.push(100099);
+    harness.batch_pay(&batch_pay_id, &all).await;
+
+    let mut funds = funds;
+    funds
+        .as_array_mut()
+        .unwrap()
+        .push(json!({"uid": 100099, "amount": 0}));
+    assert_drained(&harness, &batch_pay_id, &funds).await;
+}
+
+#[tokio::test]
+async fn test_user_trade() {
+    let harness = Harness::start(&[]);
+    let funds = json!([
+        {"uid": 100001, "amount": 100.53},
+        {"uid": 100002, "amount": 20},
+    ]);
+    harness.init_funds(&funds).await;
+    let batch_pay_id = Uuid::new_v4().to_string();
+    harness.batch_pay(&batch_pay_id, &uids(&funds)).await;
+    harness.wait_batch_pay(&batch_pay_id).await;
+
+    let trade = json!({"sourceUid": 100001, "targetUid": 100002, "amount": 0.53});
+    let request_id = Uuid::new_v4().to_string();
+    let (status, first) = harness.post_with_id("userTrade", &request_id, &trade).await;
+    assert_eq!(status, StatusCode::OK, "{first}");
+    // a retry with the same request id is answered, not traded again
+    let (status, replay) = harness.post_with_id("userTrade", &request_id, &trade).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first, replay);
+    assert_eq!(harness.balances(&[100001, 100002]).await, vec![10000, 2053]);
+
+    let too_much = json!({"sourceUid": 100002, "targetUid": 100001, "amount": 20.54});
+    let (status, body) = harness.post("userTrade", &too_much).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], 2002);
+    assert_eq!(harness.balances(&[100001, 100002]).await, vec![10000, 2053]);
+}
+
+#[tokio::test]
+async fn test_account_lifecycle() {
+    let harness = Harness::start(&[]);
+    let funds = json!([
+        {"uid": 100001, "amount": 50},
+        {"uid": 100002, "amount": 20},
+    ]);
+    harness.init_funds(&funds).await;
+    let batch_pay_id = Uuid::new_v4().to_string();
+    harness.batch_pay(&batch_pay_id, &uids(&funds)).await;
+    harness.wait_batch_pay(&batch_pay_id).await;
+    let admin = |path: &'static str, body: Value| {
+        let harness = &harness;
+        async move {
+            let (status, body) = harness.post(path, &body).await;
+            (status, body["code"].clone(), body["data"]["status"].clone())
+        }
+    };
+    let trade =
+        |source: i64, target: i64| json!({"sourceUid": source, "targetUid": target, "amount": 1});
+
+    let (status, _, state) = admin("openAccount", json!({"uid": 100003})).await;
+    assert_eq!((status, state), (StatusCode::OK, json!("active")));
+
+    // a frozen account can receive money but not send it
+    let (_, _, state) = admin("freezeAccount", json!({"uid": 100001})).await;
+    assert_eq!(state, "frozen");
+    let (status, body) = harness.post("userTrade", &trade(100001, 100002)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], 2005);
+    let (status, body) = harness.post("userTrade", &trade(100002, 100001)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (_, _, state) = admin("unfreezeAccount", json!({"uid": 100001})).await;
+    assert_eq!(state, "active");
+
+    // closing needs an empty account or somewhere to sweep the balance to
+    let (status, code, _) = admin("closeAccount", json!({"uid": 100001})).await;
+    assert_eq!((status, code), (StatusCode::CONFLICT, json!(2007)));
+    let close = json!({"uid": 100001, "sweepTo": 100003});
+    let (_, _, state) = admin("closeAccount", close.clone()).await;
+    assert_eq!(state, "closed");
+    assert_eq!(
+        harness.balances(&[100001, 100002, 100003]).await,
+        vec![0, 1900, 5100]
+    );
+    let (status, body) = harness
+        .post("accountHistory", &json!({"uid": 100003}))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["data"]["entries"][0]["kind"], "sweepCredit");
+
+    // a closed account takes nothing, not even a batch pay
+    let (status, body) = harness.post("userTrade", &trade(100002, 100001)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], 2006);
+    for (path, body) in [
+        ("closeAccount", close),
+        ("freezeAccount", json!({"uid": 100001})),
+        ("openAccount", json!({"uid": 100001})),
+    ] {
+        let (_, code, _) = admin(path, body).await;
+        assert_eq!(code, 2006, "{path}");
+    }
+    harness
+        .init_funds(&json!([{"uid": 100001, "amount": 10}]))
+        .await;
+    let batch_pay_id = Uuid::new_v4().to_string();
+    harness.batch_pay(&batch_pay_id, &[100001]).await;
+    let status = harness.wait_batch_pay(&batch_pay_id).await;
+    assert_eq!(status["failed"], json!([100001]), "{status}");
+    let upstream = harness.upstream_state().await;
+    let left: Vec<_> = upstream["balances"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|fund| fund["uid"] == 100001)
+        .map(|fund| cents(&fund["amount"]))
+        .collect();
+    assert_eq!(left, [1000]);
+}
+
+#[tokio::test]
+async fn test_lifecycle_needs_auth() {
+    let harness = Harness::start_with(&[], |config| {
+        config["auth"]["enabled"] = false.into();
+    });
+    let account = json!({"uid": 100001}).to_string();
+    for path in [
+        "openAccount",
+        "freezeAccount",
+        "unfreezeAccount",
+        "closeAccount",
+    ] {
+        let headers = harness.signed_headers(path, &Uuid::new_v4().to_string(), &account);
+        let response = harness.send(path, &headers, account.clone()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+    let (status, body) = harness.post("queryUserAmount", &json!([100001])).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+}
+
+#[tokio::test]
+async fn test_upstream_rejects_unsigned_calls() {
+    let harness = Harness::start(&[]);
+    let funds = json!([{"uid": 100001, "amount": 1}]);
+    let get_pay = json!({"transactionId": Uuid::new_v4().to_string(), "uid": 100001, "amount": 1});
+
+    let unsigned = harness.post_upstream("getPay", &get_pay, None).await;
+    assert_eq!(unsigned, StatusCode::UNAUTHORIZED);
+    let wrong_secret = Signer::new(KEY_ID, "guess");
+    let forged = harness
+        .post_upstream("initFunds", &funds, Some(&wrong_secret))
+        .await;
+    assert_eq!(forged, StatusCode::UNAUTHORIZED);
+    let upstream = harness.upstream_state().await;
+    assert_eq!(upstream["unauthorized"], 2);
+    assert_eq!(upstream["balances"], json!([]));
+
+    let signed = harness
+        .post_upstream("getPay", &get_pay, Some(&harness.signer))
+        .await;
+    assert_eq!(signed, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_api_authentication() {
+    let harness = Harness::start(&[]);
+    let query = json!([100001]).to_string();
+    let request_id = || ("X-KSY-REQUEST-ID", Uuid::new_v4().to_string());
+    let caller = |id: &str| ("X-KSY-KINGSTAR-ID", id.to_string());
+
+    let (status, body) = harness
+        .post_with_headers("queryUserAmount", &[request_id()], query.clone())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{body}");
+    assert_eq!(body["code"], 1007);
+
+    // READER_ID may query balances with its api key, but not trade
+    let mut reader = vec![
+        request_id(),
+        caller(READER_ID),
+        ("X-KSY-API-KEY", READER_KEY.into()),
+    ];
+    let (status, body) = harness
+        .post_with_headers("queryUserAmount", &reader, query.clone())
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let trade = json!({"sourceUid": 100001, "targetUid": 100002, "amount": 1}).to_string();
+    let (status, body) = harness
+        .post_with_headers("userTrade", &reader, trade.clone())
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+    assert_eq!(body["code"], 1008);
+    reader[2].1 = "guess".into();
+    let (status, _) = harness
+        .post_with_headers("queryUserAmount", &reader, query.clone())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // a signed request is accepted once, a replay is rejected
+    let signed = sign_as(&harness.caller, "queryUserAmount", CALLER_ID, "q1", &query);
+    let (status, body) = harness
+        .post_with_headers("queryUserAmount", &signed, query.clone())
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, body) = harness
+        .post_with_headers("queryUserAmount", &signed, query.clone())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["msg"], "unauthorized: signature was already used");
+
+    // a signature does not carry over to another request id, path or body
+    let mut moved = sign_as(&harness.caller, "queryUserAmount", CALLER_ID, "q2", &query);
+    moved[0] = request_id();
+    let (status, _) = harness
+        .post_with_headers("queryUserAmount", &moved, query.clone())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let forged = sign_as(&harness.caller, "queryUserAmount", CALLER_ID, "q3", &query);
+    let (status, _) = harness
+        .post_with_headers("userTrade", &forged, trade.clone())
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let tampered = sign_as(&harness.caller, "userTrade", CALLER_ID, "q4", &query);
+    let (status, _) = harness
+        .post_with_headers("userTrade", &tampered, trade)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let wrong_key = sign_as(
+        &Signer::new(KEY_ID, "guess"),
+        "queryUserAmount",
+        CALLER_ID,
+        "q5",
+        &query,
+    );
+    let (status, _) = harness
+        .post_with_headers("queryUserAmount", &wrong_key, query)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    let harness = Harness::start_with(&[("FAKE_UPSTREAM_DELAY_MS", "20")], |config| {
+        config["limits"]["per_source_uid"]["rate"] = 1.into();
+        config["limits"]["per_source_uid"]["burst"] = 2.into();
+        config["limits"]["batch_jobs"] = 1.into();
+    });
+    let funds = json!([
+        {"uid": 100001, "amount": 100},
+        {"uid": 100002, "amount": 100},
+    ]);
+    harness.init_funds(&funds).await;
+
+    // one job at a time, a batchPay turned away can come back with the same id
+    let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+    let (status, body) = harness.batch_pay(&first, &uids(&funds)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let body = json!({"batchPayId": second, "uids": [100003]}).to_string();
+    let response = harness
+        .send(
+            "batchPay",
+            &harness.signed_headers("batchPay", "b2", &body),
+            body,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["Retry-After"], "1");
+    harness.wait_batch_pay(&first).await;
+    let (status, body) = harness.batch_pay(&second, &[100003]).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    harness.wait_batch_pay(&second).await;
+
+    // 100001 may trade twice at once, then once a second
+    let trade = |source: i64| json!({"sourceUid": source, "targetUid": 100003, "amount": 1});
+    for _ in 0..2 {
+        let (status, body) = harness.post("userTrade", &trade(100001)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+    let body = trade(100001).to_string();
+    let response = harness
+        .send(
+            "userTrade",
+            &harness.signed_headers("userTrade", "t3", &body),
+            body,
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["Retry-After"], "1");
+    let body: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["code"], 1009);
+    let (status, body) = harness.post("userTrade", &trade(100002)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(harness.balances(&[100001, 100002]).await, vec![9800, 9900]);
+}